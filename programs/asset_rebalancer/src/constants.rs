@@ -3,9 +3,34 @@ pub const TOKEN_B_VAULT_STR: &str = "token_b_vault";
 pub const PC_VAULT_STR: &str = "pc_vault";
 pub const PORTFOLIO_INFO_STR: &str = "portfolio_info";
 pub const VAULT_SIGNER_STR: &str = "vault_signer";
+pub const GLOBAL_CONFIG_STR: &str = "global_config";
 pub const OPEN_ORDERS_A_STR: &str = "open_orders_a";
 pub const OPEN_ORDERS_B_STR: &str = "open_orders_b";
+pub const REBALANCE_HISTORY_STR: &str = "rebalance_history";
 
 pub const SERUM_DEX_V3_SIZE: usize = 3228;
 
+// Target and current weights are expressed in basis points of this total.
+pub const MAX_BPS: u16 = 10_000;
 
+// Most a single-leg order may lose against the oracle price, in basis points
+// of what's traded, taker fees included.
+pub const MAX_SLIPPAGE_BPS: u16 = 100;
+
+// Most a keeper can be paid for a rebalance, in basis points of the quote
+// currency traded.
+pub const MAX_KEEPER_FEE_BPS: u16 = 100;
+
+// How far, in basis points, a weight must drift from its target before a new
+// portfolio can be rebalanced, so keepers can't churn it for fees.
+pub const DEFAULT_REBALANCE_BAND_BPS: u16 = 100;
+
+// Caps on the annual management fee and the performance fee, in basis points.
+pub const MAX_MANAGEMENT_FEE_BPS: u16 = 500;
+pub const MAX_PERFORMANCE_FEE_BPS: u16 = 5_000;
+
+// Management fees accrue pro rata over a 365 day year.
+pub const SECONDS_PER_YEAR: i64 = 365 * 24 * 60 * 60;
+
+// Longest a glide path may take to reach its end allocation.
+pub const MAX_GLIDE_PATH_DURATION: i64 = 10 * SECONDS_PER_YEAR;
@@ -9,5 +9,61 @@ pub enum ErrorCode {
     #[msg("No tokens received when swapping")]
     ZeroSwap,
     #[msg("Price too old")]
-    InvalidPrice
+    InvalidPrice,
+    #[msg("Fee account must be a vault signer owned SRM/MSRM account or a quote mint account")]
+    InvalidFeeAccount,
+    #[msg("Token percentages must be non-zero and add up to 10000 basis points with the quote percentage")]
+    InvalidAllocation,
+    #[msg("Glide path must end after it starts, and within ten years")]
+    InvalidGlidePath,
+    #[msg("Math operation overflowed")]
+    MathOverflow,
+    #[msg("Price feed could not be loaded or has no valid current price")]
+    OracleUnavailable,
+    #[msg("Order size must be greater than zero")]
+    ZeroOrderSize,
+    #[msg("Market account could not be loaded")]
+    InvalidMarket,
+    #[msg("Vaults must be owned by the same account")]
+    OwnerMismatch,
+    #[msg("Token account is not one of the portfolio's vaults")]
+    VaultMismatch,
+    #[msg("Market doesn't trade the portfolio's token against its quote currency")]
+    MarketMintMismatch,
+    #[msg("Signer may not rebalance this portfolio")]
+    UnauthorizedKeeper,
+    #[msg("A rebalance delegate must be set to delegate rebalancing")]
+    MissingRebalanceDelegate,
+    #[msg("Price account is not one of the portfolio's price feeds")]
+    PriceFeedMismatch,
+    #[msg("Keeper fee exceeds the maximum keeper fee")]
+    KeeperFeeTooHigh,
+    #[msg("Rebalance band must be at most 10000 basis points")]
+    InvalidRebalanceBand,
+    #[msg("Keeper fee wallet must hold the portfolio's quote currency")]
+    KeeperFeeWalletMismatch,
+    #[msg("Management or performance fee exceeds its maximum")]
+    FeeTooHigh,
+    #[msg("A fee recipient must be set to charge fees")]
+    MissingFeeRecipient,
+    #[msg("Fee wallet must be the fee recipient's quote currency account")]
+    FeeRecipientMismatch,
+    #[msg("Signer is not the global config's admin")]
+    UnauthorizedAdmin,
+    #[msg("Instruction is paused")]
+    ProgramPaused,
+    #[msg("Portfolio is paused by its owner")]
+    PortfolioPaused,
+    #[msg("Asset is not supported against this quote currency")]
+    UnsupportedAsset,
+    #[msg("Market is not the supported asset's approved market")]
+    UnapprovedMarket,
+    #[msg("Global config can't support any more assets")]
+    TooManySupportedAssets,
+    #[msg("Signer is not the portfolio's pending owner")]
+    NotPendingOwner,
+    #[msg("Open orders account is not one of the portfolio's")]
+    OpenOrdersMismatch,
+    #[msg("Only the fee recipient or the admin can raise fees or change who they are paid to")]
+    UnauthorizedFeeChange,
 }
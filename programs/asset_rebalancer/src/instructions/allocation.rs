@@ -0,0 +1,132 @@
+use anchor_lang::prelude::*;
+
+use crate::{
+    constants::MAX_GLIDE_PATH_DURATION, errors::ErrorCode, instructions::ownership::OwnerUpdate,
+    state::PortfolioInfo,
+};
+
+/// Changes the portfolio's target allocation. With a `delay` the change is
+/// only scheduled, and takes effect on the first rebalance after it's due.
+/// Any allocation still pending is replaced.
+pub fn update_allocation(
+    ctx: Context<OwnerUpdate>,
+    token_a_percentage: u16,
+    token_b_percentage: u16,
+    pc_percentage: u16,
+    delay: i64,
+) -> Result<()> {
+    PortfolioInfo::validate_allocation(token_a_percentage, token_b_percentage, pc_percentage)?;
+    require!(delay >= 0, ErrorCode::InvalidAllocation);
+
+    let clock = Clock::get()?;
+    let portfolio_info = &mut ctx.accounts.portfolio_info;
+    let effective_unix = clock
+        .unix_timestamp
+        .checked_add(delay)
+        .ok_or(ErrorCode::MathOverflow)?;
+
+    emit!(AllocationChanged {
+        portfolio_info: portfolio_info.key(),
+        old_token_a_percentage: portfolio_info.token_a_percentage,
+        old_token_b_percentage: portfolio_info.token_b_percentage,
+        old_pc_percentage: portfolio_info.pc_percentage,
+        token_a_percentage,
+        token_b_percentage,
+        pc_percentage,
+        effective_unix,
+    });
+
+    portfolio_info.pending_token_a_percentage = token_a_percentage;
+    portfolio_info.pending_token_b_percentage = token_b_percentage;
+    portfolio_info.pending_pc_percentage = pc_percentage;
+    portfolio_info.pending_allocation_unix = effective_unix;
+    portfolio_info.apply_pending_allocation(clock.unix_timestamp);
+    Ok(())
+}
+
+/// Glides the portfolio's target allocation from a start allocation at
+/// `start_unix` to an end allocation at `end_unix`, moving the targets on
+/// every rebalance in between. The glide may last at most
+/// `MAX_GLIDE_PATH_DURATION`. Any allocation still pending is dropped.
+pub fn set_glide_path(
+    ctx: Context<OwnerUpdate>,
+    start_allocation: Allocation,
+    end_allocation: Allocation,
+    start_unix: i64,
+    end_unix: i64,
+) -> Result<()> {
+    start_allocation.validate()?;
+    end_allocation.validate()?;
+    let duration = end_unix
+        .checked_sub(start_unix)
+        .ok_or(ErrorCode::InvalidGlidePath)?;
+    require!(
+        duration > 0 && duration <= MAX_GLIDE_PATH_DURATION,
+        ErrorCode::InvalidGlidePath
+    );
+
+    let portfolio_info = &mut ctx.accounts.portfolio_info;
+    portfolio_info.glide_start_token_a_percentage = start_allocation.token_a_percentage;
+    portfolio_info.glide_start_token_b_percentage = start_allocation.token_b_percentage;
+    portfolio_info.glide_start_pc_percentage = start_allocation.pc_percentage;
+    portfolio_info.glide_end_token_a_percentage = end_allocation.token_a_percentage;
+    portfolio_info.glide_end_token_b_percentage = end_allocation.token_b_percentage;
+    portfolio_info.glide_end_pc_percentage = end_allocation.pc_percentage;
+    portfolio_info.glide_start_unix = start_unix;
+    portfolio_info.glide_end_unix = end_unix;
+    portfolio_info.pending_allocation_unix = 0;
+
+    emit!(GlidePathSet {
+        portfolio_info: portfolio_info.key(),
+        start_allocation,
+        end_allocation,
+        start_unix,
+        end_unix,
+    });
+    Ok(())
+}
+
+/// Sets the worth, in native quote units, below which rebalance orders are
+/// skipped rather than placed.
+pub fn set_dust_threshold(ctx: Context<OwnerUpdate>, dust_threshold: u64) -> Result<()> {
+    ctx.accounts.portfolio_info.dust_threshold = dust_threshold;
+    Ok(())
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct Allocation {
+    pub token_a_percentage: u16,
+    pub token_b_percentage: u16,
+    pub pc_percentage: u16,
+}
+
+impl Allocation {
+    fn validate(&self) -> Result<()> {
+        PortfolioInfo::validate_allocation(
+            self.token_a_percentage,
+            self.token_b_percentage,
+            self.pc_percentage,
+        )
+    }
+}
+
+#[event]
+pub struct AllocationChanged {
+    portfolio_info: Pubkey,
+    old_token_a_percentage: u16,
+    old_token_b_percentage: u16,
+    old_pc_percentage: u16,
+    token_a_percentage: u16,
+    token_b_percentage: u16,
+    pc_percentage: u16,
+    effective_unix: i64,
+}
+
+#[event]
+pub struct GlidePathSet {
+    portfolio_info: Pubkey,
+    start_allocation: Allocation,
+    end_allocation: Allocation,
+    start_unix: i64,
+    end_unix: i64,
+}
@@ -0,0 +1,127 @@
+use anchor_lang::prelude::*;
+
+use crate::{
+    constants::GLOBAL_CONFIG_STR,
+    errors::ErrorCode,
+    instructions::ownership::OwnerUpdate,
+    program::AssetRebalancer,
+    state::{GlobalConfig, PauseFlags, SupportedAsset},
+};
+
+/// Creates the global config, with the signer as its admin. Only the
+/// program's upgrade authority can create it, so no one can front-run the
+/// deployment and take the admin role.
+pub fn init_config(ctx: Context<InitConfig>) -> Result<()> {
+    let config = &mut ctx.accounts.config;
+    config.admin = ctx.accounts.admin.key();
+    config.pause_flags = PauseFlags::default();
+    config.bump = ctx.bumps["config"];
+    Ok(())
+}
+
+/// Hands the global config over to a new admin.
+pub fn set_config_admin(ctx: Context<UpdateConfig>, admin: Pubkey) -> Result<()> {
+    require_keys_neq!(admin, Pubkey::default(), ErrorCode::UnauthorizedAdmin);
+    ctx.accounts.config.admin = admin;
+    Ok(())
+}
+
+/// Pauses or resumes instructions across all portfolios.
+pub fn set_pause_flags(ctx: Context<UpdateConfig>, pause_flags: PauseFlags) -> Result<()> {
+    ctx.accounts.config.pause_flags = pause_flags;
+    emit!(PauseFlagsChanged { pause_flags });
+    Ok(())
+}
+
+/// Allow-lists an asset against a quote currency, with the price feed and
+/// market portfolios must use for it. Replaces the feed and market of an asset
+/// that is already supported.
+pub fn add_supported_asset(ctx: Context<UpdateConfig>, asset: SupportedAsset) -> Result<()> {
+    ctx.accounts.config.add_supported_asset(asset)?;
+    emit!(SupportedAssetAdded { asset });
+    Ok(())
+}
+
+/// Removes an asset from the allow-list. Portfolios already holding it can no
+/// longer be rebalanced, but can still be withdrawn.
+pub fn remove_supported_asset(
+    ctx: Context<UpdateConfig>,
+    mint: Pubkey,
+    pc_mint: Pubkey,
+) -> Result<()> {
+    ctx.accounts.config.remove_supported_asset(mint, pc_mint)?;
+    emit!(SupportedAssetRemoved { mint, pc_mint });
+    Ok(())
+}
+
+/// Pauses or resumes refreshing prices and rebalancing the owner's portfolio.
+/// Withdrawals stay available.
+pub fn set_portfolio_paused(ctx: Context<OwnerUpdate>, paused: bool) -> Result<()> {
+    let portfolio_info = &mut ctx.accounts.portfolio_info;
+    portfolio_info.paused = paused;
+    emit!(PortfolioPauseChanged {
+        portfolio_info: portfolio_info.key(),
+        paused,
+    });
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct InitConfig<'info> {
+    #[account(
+        init,
+        space = 8 + GlobalConfig::MAX_SIZE,
+        payer = admin,
+        seeds = [GLOBAL_CONFIG_STR.as_bytes()],
+        bump,
+    )]
+    pub config: Box<Account<'info, GlobalConfig>>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        constraint = program.programdata_address()? == Some(program_data.key())
+    )]
+    pub program: Program<'info, AssetRebalancer>,
+    #[account(
+        constraint = program_data.upgrade_authority_address == Some(admin.key())
+            @ ErrorCode::UnauthorizedAdmin
+    )]
+    pub program_data: Account<'info, ProgramData>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    #[account(
+        mut,
+        seeds = [GLOBAL_CONFIG_STR.as_bytes()],
+        bump = config.bump,
+    )]
+    pub config: Box<Account<'info, GlobalConfig>>,
+    #[account(
+        constraint = admin.key() == config.admin @ ErrorCode::UnauthorizedAdmin
+    )]
+    pub admin: Signer<'info>,
+}
+
+#[event]
+pub struct PauseFlagsChanged {
+    pause_flags: PauseFlags,
+}
+
+#[event]
+pub struct PortfolioPauseChanged {
+    portfolio_info: Pubkey,
+    paused: bool,
+}
+
+#[event]
+pub struct SupportedAssetAdded {
+    asset: SupportedAsset,
+}
+
+#[event]
+pub struct SupportedAssetRemoved {
+    mint: Pubkey,
+    pc_mint: Pubkey,
+}
@@ -1,74 +1,101 @@
+use std::collections::{BTreeMap, BTreeSet};
+
 use crate::{
-    constants::{PORTFOLIO_INFO_STR, VAULT_SIGNER_STR},
-    state::portfolio::PortfolioInfo,
+    constants::{GLOBAL_CONFIG_STR, PORTFOLIO_INFO_STR, REBALANCE_HISTORY_STR, VAULT_SIGNER_STR},
+    errors::ErrorCode,
+    instructions::{
+        fees::FeeSettlement,
+        rebalance::{
+            load_current_price, portfolio_balances, vault_worth, MarketAccounts, PortfolioBalances,
+        },
+        swap::{is_valid_markets, OpenOrdersMarkets},
+    },
+    state::{AssetInitParams, GlobalConfig, PortfolioInfo, QuoteInitParams, RebalanceHistory},
+    utils::{calculate_quote_amount, OrderbookClient},
 };
 use anchor_lang::prelude::*;
 use anchor_spl::{
     associated_token::AssociatedToken,
     token::{CloseAccount, Mint, Token, TokenAccount, Transfer},
 };
-use pyth_sdk_solana::{load_price_feed_from_account_info, Price, PriceFeed};
+use pyth_sdk_solana::Price;
 
+/// Opens a portfolio with all of the owner's token a and token b. No quote
+/// currency is deposited, so the cash sleeve starts empty and the first
+/// rebalance sells into it up to `pc_percentage`.
 pub fn deposit(
     ctx: Context<Deposit>,
     token_a_percentage: u16,
     token_b_percentage: u16,
-    vault_signer_bump: u8,
-) -> Result<()> {
-    //get prices
-    assert_eq!(
-        ctx.accounts.token_b_vault.owner.key(),
-        ctx.accounts.token_a_vault.owner.key(),
-    );
-    assert_eq!(
-        ctx.accounts.pc_vault.owner.key(),
-        ctx.accounts.token_a_vault.owner.key(),
-    );
-
-    let token_a_price_feed: PriceFeed =
-        load_price_feed_from_account_info(&ctx.accounts.token_a_pyth_price).unwrap();
-    let token_b_price_feed: PriceFeed =
-        load_price_feed_from_account_info(&ctx.accounts.token_b_pyth_price).unwrap();
-    let token_a_price: Price = token_a_price_feed.get_current_price().unwrap();
-    let token_b_price: Price = token_b_price_feed.get_current_price().unwrap();
-
-    // make transfers
-    anchor_spl::token::transfer(
-        ctx.accounts.transfer_tokens_a_to_vault(),
-        ctx.accounts.user_token_a_account.amount,
-    )
-    .expect("transfer failed");
-    anchor_spl::token::transfer(
-        ctx.accounts.transfer_tokens_b_to_vault(),
-        ctx.accounts.user_token_b_account.amount,
-    )
-    .expect("transfer failed");
-    // check that both a and b percentage adds up to 1000
-    // check chainlink price to see if assets are balanced in the expected proportion
-    PortfolioInfo::init(
-        &mut ctx.accounts.portfolio_info,
-        ctx.accounts.user.key(),
-        ctx.accounts.token_a_mint.key(),
-        ctx.accounts.token_a_mint.decimals,
-        ctx.accounts.token_b_mint.key(),
-        ctx.accounts.token_b_mint.decimals,
-        ctx.accounts.token_a_vault.key(),
-        ctx.accounts.token_b_vault.key(),
-        ctx.accounts.token_a_pyth_price.key(),
-        ctx.accounts.token_b_pyth_price.key(),
-        token_a_price.price.into(),
-        token_b_price.price.into(),
-        ctx.accounts.pc_vault.key(),
-        ctx.accounts.pc_mint.key(),
-        ctx.accounts.pc_mint.decimals,
+    pc_percentage: u16,
+) -> Result<PortfolioBalances> {
+    let vault_signer_bump = ctx.bumps["vault_signer"];
+    let rebalance_history_bump = ctx.bumps["rebalance_history"];
+    ctx.accounts.open_portfolio(
         token_a_percentage,
         token_b_percentage,
+        pc_percentage,
         vault_signer_bump,
-    )?;
-    Ok(())
+        rebalance_history_bump,
+    )
 }
 
-pub fn withdraw(ctx: Context<Withdraw>) -> Result<()> {
+pub fn withdraw<'info>(ctx: Context<'_, '_, '_, 'info, Withdraw<'info>>) -> Result<()> {
+    // If the portfolio's markets are passed as remaining accounts, cancel and
+    // settle anything still held by its open orders accounts first, so those
+    // funds are swept into the vaults before they're emptied.
+    if !ctx.remaining_accounts.is_empty() {
+        // The vaults can still be withdrawn without touching a paused market.
+        require!(
+            !ctx.accounts.config.pause_flags.withdraw,
+            ErrorCode::ProgramPaused
+        );
+        let mut remaining_accounts = ctx.remaining_accounts;
+        let markets = OpenOrdersMarkets::try_accounts(
+            ctx.program_id,
+            &mut remaining_accounts,
+            &[],
+            &mut BTreeMap::new(),
+            &mut BTreeSet::new(),
+        )?;
+        is_valid_markets(
+            &ctx.accounts.portfolio_info,
+            &markets.token_a_market,
+            &markets.token_b_market,
+        )?;
+        for market in [markets.token_a_market, markets.token_b_market] {
+            let orderbook = ctx.accounts.orderbook(market, markets.dex_program.clone());
+            orderbook.cancel_all()?;
+            orderbook.settle(None)?;
+        }
+    }
+
+    // Fees owed are paid before the owner takes the rest.
+    ctx.accounts.fee_settlement().settle(
+        &mut ctx.accounts.portfolio_info,
+        &ctx.accounts.token_a_pyth_price,
+        &ctx.accounts.token_b_pyth_price,
+    )?;
+    ctx.accounts.token_a_vault.reload()?;
+    ctx.accounts.token_b_vault.reload()?;
+    ctx.accounts.pc_vault.reload()?;
+
+    // Everything is paid out at the portfolio's last refreshed prices.
+    let withdrawn = portfolio_balances(
+        &ctx.accounts.portfolio_info,
+        &ctx.accounts.token_a_vault.to_account_info(),
+        &ctx.accounts.token_b_vault.to_account_info(),
+        &ctx.accounts.pc_vault.to_account_info(),
+    )?
+    .net_asset_value;
+    let portfolio_info = &mut ctx.accounts.portfolio_info;
+    portfolio_info.total_withdrawn = portfolio_info
+        .total_withdrawn
+        .checked_add(withdrawn)
+        .ok_or(ErrorCode::MathOverflow)?;
+    portfolio_info.token_a_cost_basis = 0;
+    portfolio_info.token_b_cost_basis = 0;
+
     let portfolio_info_key = ctx.accounts.portfolio_info.key().clone();
     //Get PDA signer seed of vault owner
     let pda_seeds = &[
@@ -83,22 +110,19 @@ pub fn withdraw(ctx: Context<Withdraw>) -> Result<()> {
             .transfer_tokens_a_from_vault()
             .with_signer(&[pda_seeds.as_ref()]),
         ctx.accounts.token_a_vault.amount,
-    )
-    .expect("transfer failed");
+    )?;
     anchor_spl::token::transfer(
         ctx.accounts
             .transfer_tokens_b_from_vault()
             .with_signer(&[pda_seeds.as_ref()]),
         ctx.accounts.token_b_vault.amount,
-    )
-    .expect("transfer failed");
+    )?;
     anchor_spl::token::transfer(
         ctx.accounts
             .transfer_pc_tokens_from_vault()
             .with_signer(&[pda_seeds.as_ref()]),
         ctx.accounts.pc_vault.amount,
-    )
-    .expect("transfer failed");
+    )?;
 
     //close accounts
     anchor_spl::token::close_account(
@@ -161,6 +185,32 @@ pub struct Withdraw<'info> {
     )]
     user_pc_account: Box<Account<'info, TokenAccount>>,
     pc_mint: Account<'info, Mint>,
+    /// CHECK: Must be the portfolio's token a price feed.
+    #[account(address = portfolio_info.token_a_price_feed @ ErrorCode::PriceFeedMismatch)]
+    token_a_pyth_price: AccountInfo<'info>,
+    /// CHECK: Must be the portfolio's token b price feed.
+    #[account(address = portfolio_info.token_b_price_feed @ ErrorCode::PriceFeedMismatch)]
+    token_b_pyth_price: AccountInfo<'info>,
+    // The fee recipient's accounts fees owed are settled into. Any account of
+    // the right mint will do for a portfolio that charges no fees.
+    #[account(
+        mut,
+        constraint = fee_token_a_wallet.mint == portfolio_info.token_a_mint @ ErrorCode::FeeRecipientMismatch,
+        constraint = !portfolio_info.charges_fees() || fee_token_a_wallet.owner == portfolio_info.fee_recipient @ ErrorCode::FeeRecipientMismatch,
+    )]
+    fee_token_a_wallet: Box<Account<'info, TokenAccount>>,
+    #[account(
+        mut,
+        constraint = fee_token_b_wallet.mint == portfolio_info.token_b_mint @ ErrorCode::FeeRecipientMismatch,
+        constraint = !portfolio_info.charges_fees() || fee_token_b_wallet.owner == portfolio_info.fee_recipient @ ErrorCode::FeeRecipientMismatch,
+    )]
+    fee_token_b_wallet: Box<Account<'info, TokenAccount>>,
+    #[account(
+        mut,
+        constraint = fee_pc_wallet.mint == portfolio_info.pc_mint @ ErrorCode::FeeRecipientMismatch,
+        constraint = !portfolio_info.charges_fees() || fee_pc_wallet.owner == portfolio_info.fee_recipient @ ErrorCode::FeeRecipientMismatch,
+    )]
+    fee_pc_wallet: Box<Account<'info, TokenAccount>>,
     /// CHECK: This is the vault signer Acct
     #[account(
         seeds = [VAULT_SIGNER_STR.as_bytes(), portfolio_info.key().as_ref()],
@@ -169,10 +219,15 @@ pub struct Withdraw<'info> {
     vault_signer: AccountInfo<'info>,
     #[account(
         mut,
-        seeds = [PORTFOLIO_INFO_STR.as_bytes(), user.key().as_ref()],
+        seeds = [PORTFOLIO_INFO_STR.as_bytes(), portfolio_info.creator.as_ref()],
         bump,
     )]
     portfolio_info: Box<Account<'info, PortfolioInfo>>,
+    #[account(
+        seeds = [GLOBAL_CONFIG_STR.as_bytes()],
+        bump = config.bump,
+    )]
+    config: Box<Account<'info, GlobalConfig>>,
     #[account(
         mut,
         constraint = user.key() == portfolio_info.owner
@@ -184,6 +239,34 @@ pub struct Withdraw<'info> {
 }
 
 impl<'info> Withdraw<'info> {
+    fn fee_settlement(&self) -> FeeSettlement<'info> {
+        FeeSettlement {
+            token_a_vault: self.token_a_vault.to_account_info(),
+            token_b_vault: self.token_b_vault.to_account_info(),
+            pc_vault: self.pc_vault.to_account_info(),
+            fee_token_a_wallet: self.fee_token_a_wallet.to_account_info(),
+            fee_token_b_wallet: self.fee_token_b_wallet.to_account_info(),
+            fee_pc_wallet: self.fee_pc_wallet.to_account_info(),
+            vault_signer: self.vault_signer.clone(),
+            token_program: self.token_program.to_account_info(),
+        }
+    }
+
+    fn orderbook(
+        &self,
+        market: MarketAccounts<'info>,
+        dex_program: AccountInfo<'info>,
+    ) -> OrderbookClient<'info> {
+        OrderbookClient {
+            market,
+            authority: self.vault_signer.clone(),
+            pc_wallet: self.pc_vault.to_account_info().clone(),
+            dex_program,
+            token_program: self.token_program.to_account_info().clone(),
+            rent: self.rent.to_account_info().clone(),
+            portfolio_info: self.portfolio_info.clone(),
+        }
+    }
     pub fn transfer_tokens_a_from_vault(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
         let transfer_acct = Transfer {
             from: self.token_a_vault.to_account_info().clone(),
@@ -241,72 +324,205 @@ impl<'info> Withdraw<'info> {
     }
 }
 #[derive(Accounts)]
-#[instruction(token_a_percentage: u16, token_b_percentage: u16, vault_signer_bump: u8)]
+#[instruction(
+    token_a_percentage: u16,
+    token_b_percentage: u16,
+    pc_percentage: u16,
+)]
 pub struct Deposit<'info> {
-    token_a_mint: Account<'info, Mint>,
+    pub token_a_mint: Account<'info, Mint>,
     #[account(
         mut,
         token::mint=token_a_mint,
         token::authority=user,
     )]
-    user_token_a_account: Box<Account<'info, TokenAccount>>,
+    pub user_token_a_account: Box<Account<'info, TokenAccount>>,
     #[account(
         init_if_needed,
-        payer = user,
+        payer = payer,
         associated_token::mint=token_a_mint,
         associated_token::authority=vault_signer,
     )]
-    token_a_vault: Box<Account<'info, TokenAccount>>,
+    pub token_a_vault: Box<Account<'info, TokenAccount>>,
 
-    token_b_mint: Account<'info, Mint>,
+    pub token_b_mint: Account<'info, Mint>,
     #[account(
         mut,
         token::mint=token_b_mint,
         token::authority=user,
     )]
-    user_token_b_account: Box<Account<'info, TokenAccount>>,
+    pub user_token_b_account: Box<Account<'info, TokenAccount>>,
     #[account(
         init_if_needed,
-        payer = user,
+        payer = payer,
         associated_token::mint=token_b_mint,
         associated_token::authority=vault_signer,
     )]
-    token_b_vault: Box<Account<'info, TokenAccount>>,
+    pub token_b_vault: Box<Account<'info, TokenAccount>>,
     #[account(
         init_if_needed,
-        payer = user,
+        payer = payer,
         associated_token::mint=pc_mint,
         associated_token::authority=vault_signer,
     )]
-    pc_vault: Box<Account<'info, TokenAccount>>,
-    pc_mint: Account<'info, Mint>,
+    pub pc_vault: Box<Account<'info, TokenAccount>>,
+    pub pc_mint: Account<'info, Mint>,
     /// CHECK: This is the vault signer Acct
     #[account(
         seeds = [VAULT_SIGNER_STR.as_bytes(), portfolio_info.key().as_ref()],
-        bump = vault_signer_bump,
+        bump,
     )]
-    vault_signer: AccountInfo<'info>,
+    pub vault_signer: AccountInfo<'info>,
     #[account(
         init,
         space = 8 + PortfolioInfo::MAX_SIZE ,
-        payer = user,
+        payer = payer,
         seeds = [PORTFOLIO_INFO_STR.as_bytes(), user.key().as_ref()],
         bump,
     )]
-    portfolio_info: Box<Account<'info, PortfolioInfo>>,
+    pub portfolio_info: Box<Account<'info, PortfolioInfo>>,
+    #[account(
+        init,
+        space = 8 + RebalanceHistory::MAX_SIZE,
+        payer = payer,
+        seeds = [REBALANCE_HISTORY_STR.as_bytes(), portfolio_info.key().as_ref()],
+        bump,
+    )]
+    pub rebalance_history: Box<Account<'info, RebalanceHistory>>,
+    #[account(
+        seeds = [GLOBAL_CONFIG_STR.as_bytes()],
+        bump = config.bump,
+        constraint = !config.pause_flags.deposit @ ErrorCode::ProgramPaused,
+    )]
+    pub config: Box<Account<'info, GlobalConfig>>,
     /// CHECK:`
-    token_a_pyth_price: AccountInfo<'info>,
+    pub token_a_pyth_price: AccountInfo<'info>,
     /// CHECK:`
-    token_b_pyth_price: AccountInfo<'info>,
+    pub token_b_pyth_price: AccountInfo<'info>,
+    // The portfolio's owner, which the portfolio's address is derived from.
+    // May be a multisig or a PDA signing through CPI.
+    pub user: Signer<'info>,
+    // Pays for the portfolio's accounts.
     #[account(mut)]
-    user: Signer<'info>,
-    token_program: Program<'info, Token>,
-    associated_token_program: Program<'info, AssociatedToken>,
-    rent: Sysvar<'info, Rent>,
-    system_program: Program<'info, System>,
+    pub payer: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
 }
 
 impl<'info> Deposit<'info> {
+    // Moves the owner's tokens into the portfolio's vaults and initializes the
+    // portfolio with them.
+    pub fn open_portfolio(
+        &mut self,
+        token_a_percentage: u16,
+        token_b_percentage: u16,
+        pc_percentage: u16,
+        vault_signer_bump: u8,
+        rebalance_history_bump: u8,
+    ) -> Result<PortfolioBalances> {
+        PortfolioInfo::validate_allocation(token_a_percentage, token_b_percentage, pc_percentage)?;
+
+        require_keys_eq!(
+            self.token_b_vault.owner.key(),
+            self.token_a_vault.owner.key(),
+            ErrorCode::OwnerMismatch
+        );
+        require_keys_eq!(
+            self.pc_vault.owner.key(),
+            self.token_a_vault.owner.key(),
+            ErrorCode::OwnerMismatch
+        );
+
+        // Only allow-listed assets, priced by their canonical feeds, are accepted.
+        let config = &self.config;
+        let pc_mint = self.pc_mint.key();
+        config.validate_price_feed(
+            self.token_a_mint.key(),
+            pc_mint,
+            self.token_a_pyth_price.key(),
+        )?;
+        config.validate_price_feed(
+            self.token_b_mint.key(),
+            pc_mint,
+            self.token_b_pyth_price.key(),
+        )?;
+
+        //get prices
+        let token_a_price: Price = load_current_price(&self.token_a_pyth_price)?;
+        let token_b_price: Price = load_current_price(&self.token_b_pyth_price)?;
+
+        // make transfers
+        anchor_spl::token::transfer(
+            self.transfer_tokens_a_to_vault(),
+            self.user_token_a_account.amount,
+        )?;
+        anchor_spl::token::transfer(
+            self.transfer_tokens_b_to_vault(),
+            self.user_token_b_account.amount,
+        )?;
+        // check chainlink price to see if assets are balanced in the expected proportion
+        PortfolioInfo::init(
+            &mut self.portfolio_info,
+            self.user.key(),
+            AssetInitParams {
+                mint: self.token_a_mint.key(),
+                decimals: self.token_a_mint.decimals,
+                vault: self.token_a_vault.key(),
+                price_feed: self.token_a_pyth_price.key(),
+                price: token_a_price.price.into(),
+                price_expo: token_a_price.expo,
+                percentage: token_a_percentage,
+            },
+            AssetInitParams {
+                mint: self.token_b_mint.key(),
+                decimals: self.token_b_mint.decimals,
+                vault: self.token_b_vault.key(),
+                price_feed: self.token_b_pyth_price.key(),
+                price: token_b_price.price.into(),
+                price_expo: token_b_price.expo,
+                percentage: token_b_percentage,
+            },
+            QuoteInitParams {
+                mint: self.pc_mint.key(),
+                decimals: self.pc_mint.decimals,
+                vault: self.pc_vault.key(),
+                percentage: pc_percentage,
+            },
+            vault_signer_bump,
+        )?;
+        self.rebalance_history.portfolio_info = self.portfolio_info.key();
+        self.rebalance_history.bump = rebalance_history_bump;
+
+        // The vaults' opening balances are what performance is measured from.
+        let token_a_vault = self.token_a_vault.to_account_info();
+        let token_b_vault = self.token_b_vault.to_account_info();
+        let pc_vault = self.pc_vault.to_account_info();
+        let balances = portfolio_balances(
+            &self.portfolio_info,
+            &token_a_vault,
+            &token_b_vault,
+            &pc_vault,
+        )?;
+        let valuation = vault_worth(
+            &self.portfolio_info,
+            &token_a_vault,
+            &token_b_vault,
+            &pc_vault,
+        )?;
+        let portfolio_info = &mut self.portfolio_info;
+        portfolio_info.token_a_cost_basis =
+            calculate_quote_amount(valuation.token_a_worth, portfolio_info.pc_decimals)?;
+        portfolio_info.token_b_cost_basis =
+            calculate_quote_amount(valuation.token_b_worth, portfolio_info.pc_decimals)?;
+        portfolio_info.total_deposited = balances.net_asset_value;
+        portfolio_info.token_a_initial_amount = balances.token_a_amount;
+        portfolio_info.token_b_initial_amount = balances.token_b_amount;
+        portfolio_info.pc_initial_amount = balances.pc_amount;
+        Ok(balances)
+    }
+
     pub fn transfer_tokens_a_to_vault(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
         let transfer_acct = Transfer {
             to: self.token_a_vault.to_account_info().clone(),
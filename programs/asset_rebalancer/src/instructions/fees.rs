@@ -0,0 +1,419 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token::{self, Token, TokenAccount, Transfer};
+
+use crate::{
+    constants::{
+        GLOBAL_CONFIG_STR, MAX_MANAGEMENT_FEE_BPS, MAX_PERFORMANCE_FEE_BPS, PORTFOLIO_INFO_STR,
+        VAULT_SIGNER_STR,
+    },
+    errors::ErrorCode,
+    instructions::rebalance::{at_current_prices, token_a_holding, token_b_holding, vault_worth},
+    state::{Decimal, GlobalConfig, PortfolioInfo, Rounding},
+    utils::{
+        calculate_amount_for_worth, calculate_management_fee, calculate_performance_fee,
+        calculate_quote_amount, Holding,
+    },
+};
+
+/// Sets the annual management fee and the performance fee over the high-water
+/// mark, both in basis points, and who they are paid to. Fees accrued so far
+/// are collected at the old rates first. The owner can only lower fees; raising
+/// them or changing who they are paid to takes the fee recipient or the admin.
+pub fn set_fees(
+    ctx: Context<SetFees>,
+    fee_recipient: Pubkey,
+    management_fee_bps: u16,
+    performance_fee_bps: u16,
+) -> Result<()> {
+    require!(
+        management_fee_bps <= MAX_MANAGEMENT_FEE_BPS
+            && performance_fee_bps <= MAX_PERFORMANCE_FEE_BPS,
+        ErrorCode::FeeTooHigh
+    );
+    require!(
+        fee_recipient != Pubkey::default() || (management_fee_bps == 0 && performance_fee_bps == 0),
+        ErrorCode::MissingFeeRecipient
+    );
+
+    let portfolio_info = &ctx.accounts.collect.portfolio_info;
+    let authority = ctx.accounts.authority.key();
+    let fee_authority =
+        authority == portfolio_info.fee_recipient || authority == ctx.accounts.config.admin;
+    require!(
+        fee_authority || authority == portfolio_info.owner,
+        ErrorCode::UnauthorizedFeeChange
+    );
+    // Until a fee recipient is set there is nobody to protect.
+    let raises_fees = portfolio_info.fee_recipient != Pubkey::default()
+        && (fee_recipient != portfolio_info.fee_recipient
+            || management_fee_bps > portfolio_info.management_fee_bps
+            || performance_fee_bps > portfolio_info.performance_fee_bps);
+    require!(
+        fee_authority || !raises_fees,
+        ErrorCode::UnauthorizedFeeChange
+    );
+
+    if ctx.accounts.collect.portfolio_info.charges_fees() {
+        ctx.accounts.collect.collect()?;
+    } else {
+        // Nothing accrued at zero rates, so the new ones only apply from now,
+        // and the high-water mark starts over at the next accrual.
+        let portfolio_info = &mut ctx.accounts.collect.portfolio_info;
+        portfolio_info.last_fee_accrual_unix = Clock::get()?.unix_timestamp;
+        portfolio_info.high_water_mark = 0;
+    }
+
+    let portfolio_info = &mut ctx.accounts.collect.portfolio_info;
+    portfolio_info.fee_recipient = fee_recipient;
+    portfolio_info.management_fee_bps = management_fee_bps;
+    portfolio_info.performance_fee_bps = performance_fee_bps;
+    Ok(())
+}
+
+/// Accrues management fees for the time since the last collection and
+/// performance fees on net asset value above the high-water mark, valued at
+/// the price feeds' current prices, and pays them to the fee recipient out of
+/// the quote vault. Whatever the quote vault can't cover is carried over, and
+/// the next rebalance sells into the quote sleeve to cover it. Anyone may
+/// crank it.
+pub fn collect_fees(ctx: Context<CollectFees>) -> Result<()> {
+    ctx.accounts.collect()
+}
+
+// Fees a single accrual added, in native quote units, and the net asset value
+// left after them.
+pub struct AccruedFees {
+    pub management_fee: u64,
+    pub performance_fee: u64,
+    pub net_asset_value: u64,
+}
+
+// Accrues management fees for the time since the last accrual and performance
+// fees above the high-water mark onto the portfolio's unpaid fees.
+// `net_asset_value` is already net of the fees owed before. The first accrual
+// only marks where the high-water mark starts.
+pub fn accrue_fees(
+    portfolio_info: &mut PortfolioInfo,
+    net_asset_value: u64,
+    unix_timestamp: i64,
+) -> Result<AccruedFees> {
+    let elapsed = unix_timestamp
+        .saturating_sub(portfolio_info.last_fee_accrual_unix)
+        .max(0);
+    let management_fee =
+        calculate_management_fee(net_asset_value, portfolio_info.management_fee_bps, elapsed)?;
+    let net_asset_value = net_asset_value.saturating_sub(management_fee);
+
+    let performance_fee = if portfolio_info.high_water_mark == 0 {
+        0
+    } else {
+        calculate_performance_fee(
+            net_asset_value,
+            portfolio_info.high_water_mark,
+            portfolio_info.performance_fee_bps,
+        )?
+    };
+    let net_asset_value = net_asset_value.saturating_sub(performance_fee);
+
+    portfolio_info.unpaid_fees = portfolio_info
+        .unpaid_fees
+        .checked_add(management_fee)
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_add(performance_fee)
+        .ok_or(ErrorCode::MathOverflow)?;
+    portfolio_info.high_water_mark = portfolio_info.high_water_mark.max(net_asset_value);
+    portfolio_info.last_fee_accrual_unix = unix_timestamp;
+    Ok(AccruedFees {
+        management_fee,
+        performance_fee,
+        net_asset_value,
+    })
+}
+
+// Accounts fees are paid out of, and to, when the owner takes everything out
+// of the portfolio.
+pub struct FeeSettlement<'info> {
+    pub token_a_vault: AccountInfo<'info>,
+    pub token_b_vault: AccountInfo<'info>,
+    pub pc_vault: AccountInfo<'info>,
+    pub fee_token_a_wallet: AccountInfo<'info>,
+    pub fee_token_b_wallet: AccountInfo<'info>,
+    pub fee_pc_wallet: AccountInfo<'info>,
+    pub vault_signer: AccountInfo<'info>,
+    pub token_program: AccountInfo<'info>,
+}
+
+impl<'info> FeeSettlement<'info> {
+    // Accrues fees at the price feeds' current prices and pays everything
+    // owed before the owner takes the rest: out of the quote vault first, then
+    // in kind out of the token a and token b vaults. A portfolio that charges
+    // no fees and owes none is left alone, without reading its price feeds.
+    // Should the feeds be stale or unavailable, fees are valued at the
+    // portfolio's last refreshed prices instead, so an oracle outage never
+    // blocks the exit. Whatever the vaults can't cover stays in unpaid fees.
+    pub fn settle(
+        &self,
+        portfolio_info: &mut Account<'info, PortfolioInfo>,
+        token_a_pyth_price: &AccountInfo<'info>,
+        token_b_pyth_price: &AccountInfo<'info>,
+    ) -> Result<()> {
+        if !portfolio_info.charges_fees() {
+            return Ok(());
+        }
+        let clock = Clock::get()?;
+        let priced = at_current_prices(portfolio_info, token_a_pyth_price, token_b_pyth_price)
+            .unwrap_or(**portfolio_info);
+        let valuation = vault_worth(
+            &priced,
+            &self.token_a_vault,
+            &self.token_b_vault,
+            &self.pc_vault,
+        )?;
+        let net_asset_value = calculate_quote_amount(valuation.total_worth, priced.pc_decimals)?;
+        let accrued = accrue_fees(portfolio_info, net_asset_value, clock.unix_timestamp)?;
+
+        let portfolio_info_key = portfolio_info.key();
+        let pda_seeds = &[
+            VAULT_SIGNER_STR.as_bytes(),
+            portfolio_info_key.as_ref(),
+            &[portfolio_info.vault_signer_bump],
+        ];
+        let signer = &[pda_seeds.as_ref()];
+
+        let fees_owed = portfolio_info.unpaid_fees;
+        let pc_paid = fees_owed.min(token::accessor::amount(&self.pc_vault)?);
+        self.transfer(&self.pc_vault, &self.fee_pc_wallet, pc_paid, signer)?;
+        let mut unpaid_fees = fees_owed - pc_paid;
+
+        // Whatever the quote vault can't cover is paid in kind, rounded up in
+        // the fee recipient's favour.
+        for (vault, fee_wallet, holding) in [
+            (
+                &self.token_a_vault,
+                &self.fee_token_a_wallet,
+                token_a_holding(&priced, token::accessor::amount(&self.token_a_vault)?)?,
+            ),
+            (
+                &self.token_b_vault,
+                &self.fee_token_b_wallet,
+                token_b_holding(&priced, token::accessor::amount(&self.token_b_vault)?)?,
+            ),
+        ] {
+            if unpaid_fees == 0 {
+                break;
+            }
+            let amount_paid = calculate_amount_for_worth(
+                holding.amount,
+                holding.worth()?,
+                Decimal::from_token_amount(unpaid_fees, priced.pc_decimals),
+                Rounding::Up,
+            )?;
+            self.transfer(vault, fee_wallet, amount_paid, signer)?;
+            let paid = Holding {
+                amount: amount_paid,
+                ..holding
+            };
+            let worth_paid = calculate_quote_amount(paid.worth()?, priced.pc_decimals)?;
+            unpaid_fees = unpaid_fees.saturating_sub(worth_paid);
+        }
+
+        portfolio_info.unpaid_fees = unpaid_fees;
+        emit_fees_accrued(portfolio_info, &accrued, fees_owed - unpaid_fees);
+        Ok(())
+    }
+
+    fn transfer(
+        &self,
+        from: &AccountInfo<'info>,
+        to: &AccountInfo<'info>,
+        amount: u64,
+        signer: &[&[&[u8]]],
+    ) -> Result<()> {
+        if amount == 0 {
+            return Ok(());
+        }
+        let transfer_acct = Transfer {
+            from: from.clone(),
+            to: to.clone(),
+            authority: self.vault_signer.clone(),
+        };
+        token::transfer(
+            CpiContext::new(self.token_program.clone(), transfer_acct).with_signer(signer),
+            amount,
+        )
+    }
+}
+
+fn emit_fees_accrued(
+    portfolio_info: &Account<PortfolioInfo>,
+    accrued: &AccruedFees,
+    fees_paid: u64,
+) {
+    emit!(FeesAccrued {
+        portfolio_info: portfolio_info.key(),
+        fee_recipient: portfolio_info.fee_recipient,
+        management_fee: accrued.management_fee,
+        performance_fee: accrued.performance_fee,
+        fees_paid,
+        unpaid_fees: portfolio_info.unpaid_fees,
+        net_asset_value: accrued.net_asset_value,
+        high_water_mark: portfolio_info.high_water_mark,
+    });
+}
+
+#[derive(Accounts)]
+pub struct CollectFees<'info> {
+    #[account(
+        mut,
+        seeds = [PORTFOLIO_INFO_STR.as_bytes(), portfolio_info.creator.as_ref()],
+        bump,
+    )]
+    portfolio_info: Box<Account<'info, PortfolioInfo>>,
+    /// CHECK: Must be the portfolio's token a price feed.
+    #[account(address = portfolio_info.token_a_price_feed @ ErrorCode::PriceFeedMismatch)]
+    token_a_pyth_price: AccountInfo<'info>,
+    /// CHECK: Must be the portfolio's token b price feed.
+    #[account(address = portfolio_info.token_b_price_feed @ ErrorCode::PriceFeedMismatch)]
+    token_b_pyth_price: AccountInfo<'info>,
+    #[account(address = portfolio_info.token_a_vault @ ErrorCode::VaultMismatch)]
+    token_a_vault: Box<Account<'info, TokenAccount>>,
+    #[account(address = portfolio_info.token_b_vault @ ErrorCode::VaultMismatch)]
+    token_b_vault: Box<Account<'info, TokenAccount>>,
+    #[account(
+        mut,
+        address = portfolio_info.pc_vault @ ErrorCode::VaultMismatch
+    )]
+    pc_vault: Box<Account<'info, TokenAccount>>,
+    /// CHECK: This is the vault signer Acct
+    #[account(
+        seeds = [VAULT_SIGNER_STR.as_bytes(), portfolio_info.key().as_ref()],
+        bump = portfolio_info.vault_signer_bump,
+    )]
+    vault_signer: AccountInfo<'info>,
+    // The fee recipient's quote currency account. Any account of the right
+    // mint will do for a portfolio that charges no fees.
+    #[account(
+        mut,
+        constraint = fee_wallet.mint == portfolio_info.pc_mint @ ErrorCode::FeeRecipientMismatch,
+        constraint = !portfolio_info.charges_fees() || fee_wallet.owner == portfolio_info.fee_recipient @ ErrorCode::FeeRecipientMismatch,
+    )]
+    fee_wallet: Box<Account<'info, TokenAccount>>,
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct SetFees<'info> {
+    // Fees owed at the old rates are collected through these first.
+    pub collect: CollectFees<'info>,
+    // The owner, the fee recipient or the admin.
+    pub authority: Signer<'info>,
+    #[account(
+        seeds = [GLOBAL_CONFIG_STR.as_bytes()],
+        bump = config.bump,
+    )]
+    pub config: Box<Account<'info, GlobalConfig>>,
+}
+
+impl<'info> CollectFees<'info> {
+    fn collect(&mut self) -> Result<()> {
+        let clock = Clock::get()?;
+        let priced = at_current_prices(
+            &self.portfolio_info,
+            &self.token_a_pyth_price,
+            &self.token_b_pyth_price,
+        )?;
+        let valuation = vault_worth(
+            &priced,
+            &self.token_a_vault.to_account_info(),
+            &self.token_b_vault.to_account_info(),
+            &self.pc_vault.to_account_info(),
+        )?;
+        let net_asset_value = calculate_quote_amount(valuation.total_worth, priced.pc_decimals)?;
+        let accrued = accrue_fees(
+            &mut self.portfolio_info,
+            net_asset_value,
+            clock.unix_timestamp,
+        )?;
+
+        let portfolio_info = &self.portfolio_info;
+        let fees_owed = portfolio_info.unpaid_fees;
+        let fees_paid = fees_owed.min(self.pc_vault.amount);
+        if fees_paid > 0 {
+            let portfolio_info_key = portfolio_info.key();
+            let pda_seeds = &[
+                VAULT_SIGNER_STR.as_bytes(),
+                portfolio_info_key.as_ref(),
+                &[portfolio_info.vault_signer_bump],
+            ];
+            token::transfer(
+                self.transfer_fees_from_vault()
+                    .with_signer(&[pda_seeds.as_ref()]),
+                fees_paid,
+            )?;
+        }
+
+        let portfolio_info = &mut self.portfolio_info;
+        portfolio_info.unpaid_fees = fees_owed - fees_paid;
+        emit_fees_accrued(portfolio_info, &accrued, fees_paid);
+        Ok(())
+    }
+
+    pub fn transfer_fees_from_vault(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let transfer_acct = Transfer {
+            from: self.pc_vault.to_account_info().clone(),
+            to: self.fee_wallet.to_account_info().clone(),
+            authority: self.vault_signer.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info(), transfer_acct)
+    }
+}
+
+// Fees are in native units of the quote currency, as are the net asset value
+// and high-water mark after the accrual.
+#[event]
+pub struct FeesAccrued {
+    portfolio_info: Pubkey,
+    fee_recipient: Pubkey,
+    management_fee: u64,
+    performance_fee: u64,
+    fees_paid: u64,
+    unpaid_fees: u64,
+    net_asset_value: u64,
+    high_water_mark: u64,
+}
+
+#[test]
+pub fn test_accrue_fees() {
+    let day = 24 * 60 * 60;
+    let mut portfolio_info = PortfolioInfo {
+        management_fee_bps: 200,
+        performance_fee_bps: 2000,
+        last_fee_accrual_unix: 0,
+        ..Default::default()
+    };
+
+    // The first accrual only starts the high-water mark.
+    let accrued = accrue_fees(&mut portfolio_info, 1_000_000_000, 0).unwrap();
+    assert_eq!(accrued.management_fee, 0);
+    assert_eq!(accrued.performance_fee, 0);
+    assert_eq!(portfolio_info.high_water_mark, 1_000_000_000);
+    assert_eq!(portfolio_info.unpaid_fees, 0);
+
+    // A year at 2% with no gain only charges the management fee.
+    let accrued = accrue_fees(&mut portfolio_info, 1_000_000_000, 365 * day).unwrap();
+    assert_eq!(accrued.management_fee, 20_000_000);
+    assert_eq!(accrued.performance_fee, 0);
+    assert_eq!(accrued.net_asset_value, 980_000_000);
+    assert_eq!(portfolio_info.unpaid_fees, 20_000_000);
+    assert_eq!(portfolio_info.high_water_mark, 1_000_000_000);
+    assert_eq!(portfolio_info.last_fee_accrual_unix, 365 * day);
+
+    // Only part of it is paid, the rest carries over and is added to.
+    portfolio_info.unpaid_fees -= 5_000_000;
+    let accrued = accrue_fees(&mut portfolio_info, 1_200_000_000, 365 * day).unwrap();
+    assert_eq!(accrued.management_fee, 0);
+    // 20% of the 200 USDC gained above the high-water mark.
+    assert_eq!(accrued.performance_fee, 40_000_000);
+    assert_eq!(portfolio_info.unpaid_fees, 55_000_000);
+    assert_eq!(portfolio_info.high_water_mark, 1_160_000_000);
+}
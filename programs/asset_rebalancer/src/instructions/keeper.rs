@@ -0,0 +1,111 @@
+use anchor_lang::prelude::*;
+
+use crate::{
+    constants::{MAX_BPS, MAX_KEEPER_FEE_BPS},
+    errors::ErrorCode,
+    instructions::ownership::OwnerUpdate,
+    state::{PortfolioInfo, RebalancePolicy},
+};
+
+/// Sets who may refresh prices and rebalance the portfolio. Delegating
+/// requires a delegate to have been set first.
+pub fn set_rebalance_policy(
+    ctx: Context<OwnerUpdate>,
+    rebalance_policy: RebalancePolicy,
+) -> Result<()> {
+    let portfolio_info = &mut ctx.accounts.portfolio_info;
+    require!(
+        rebalance_policy != RebalancePolicy::Delegate
+            || portfolio_info.rebalance_delegate != Pubkey::default(),
+        ErrorCode::MissingRebalanceDelegate
+    );
+    portfolio_info.rebalance_policy = rebalance_policy;
+    emit_keeper_change(portfolio_info);
+    Ok(())
+}
+
+/// Delegates rebalancing to `delegate`, replacing any previous delegate.
+pub fn set_rebalance_delegate(ctx: Context<OwnerUpdate>, delegate: Pubkey) -> Result<()> {
+    require_keys_neq!(
+        delegate,
+        Pubkey::default(),
+        ErrorCode::MissingRebalanceDelegate
+    );
+    let portfolio_info = &mut ctx.accounts.portfolio_info;
+    portfolio_info.rebalance_delegate = delegate;
+    portfolio_info.rebalance_policy = RebalancePolicy::Delegate;
+    emit_keeper_change(portfolio_info);
+    Ok(())
+}
+
+/// Revokes the rebalance delegate. A portfolio that was delegated falls back
+/// to owner-only rebalancing.
+pub fn revoke_rebalance_delegate(ctx: Context<OwnerUpdate>) -> Result<()> {
+    let portfolio_info = &mut ctx.accounts.portfolio_info;
+    portfolio_info.rebalance_delegate = Pubkey::default();
+    if portfolio_info.rebalance_policy == RebalancePolicy::Delegate {
+        portfolio_info.rebalance_policy = RebalancePolicy::OwnerOnly;
+    }
+    emit_keeper_change(portfolio_info);
+    Ok(())
+}
+
+/// Sets the fee paid to whoever executes a rebalance that trades, in basis
+/// points of the quote currency traded. Capped at `MAX_KEEPER_FEE_BPS`.
+pub fn set_keeper_fee(ctx: Context<OwnerUpdate>, keeper_fee_bps: u16) -> Result<()> {
+    require!(
+        keeper_fee_bps <= MAX_KEEPER_FEE_BPS,
+        ErrorCode::KeeperFeeTooHigh
+    );
+    let portfolio_info = &mut ctx.accounts.portfolio_info;
+    portfolio_info.keeper_fee_bps = keeper_fee_bps;
+    emit!(KeeperFeeChanged {
+        portfolio_info: portfolio_info.key(),
+        keeper_fee_bps,
+    });
+    Ok(())
+}
+
+/// Sets how far, in basis points, a weight must drift from its target before
+/// a rebalance trades. Drift inside the band is left alone, which keeps
+/// keepers from churning the portfolio for fees.
+pub fn set_rebalance_band(ctx: Context<OwnerUpdate>, rebalance_band_bps: u16) -> Result<()> {
+    require!(
+        rebalance_band_bps <= MAX_BPS,
+        ErrorCode::InvalidRebalanceBand
+    );
+    let portfolio_info = &mut ctx.accounts.portfolio_info;
+    portfolio_info.rebalance_band_bps = rebalance_band_bps;
+    emit!(RebalanceBandChanged {
+        portfolio_info: portfolio_info.key(),
+        rebalance_band_bps,
+    });
+    Ok(())
+}
+
+fn emit_keeper_change(portfolio_info: &Account<PortfolioInfo>) {
+    emit!(RebalancePolicyChanged {
+        portfolio_info: portfolio_info.key(),
+        rebalance_policy: portfolio_info.rebalance_policy,
+        rebalance_delegate: portfolio_info.rebalance_delegate,
+    });
+}
+
+#[event]
+pub struct RebalancePolicyChanged {
+    portfolio_info: Pubkey,
+    rebalance_policy: RebalancePolicy,
+    rebalance_delegate: Pubkey,
+}
+
+#[event]
+pub struct KeeperFeeChanged {
+    portfolio_info: Pubkey,
+    keeper_fee_bps: u16,
+}
+
+#[event]
+pub struct RebalanceBandChanged {
+    portfolio_info: Pubkey,
+    rebalance_band_bps: u16,
+}
@@ -1,7 +1,19 @@
+pub mod allocation;
+pub mod config;
 pub mod deposit_withdraw;
+pub mod fees;
+pub mod keeper;
+pub mod onboarding;
+pub mod ownership;
 pub mod rebalance;
 pub mod swap;
 
+pub use allocation::*;
+pub use config::*;
 pub use deposit_withdraw::*;
+pub use fees::*;
+pub use keeper::*;
+pub use onboarding::*;
+pub use ownership::*;
 pub use rebalance::*;
 pub use swap::*;
\ No newline at end of file
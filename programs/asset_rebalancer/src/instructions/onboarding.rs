@@ -0,0 +1,461 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use anchor_lang::prelude::*;
+use anchor_spl::dex::serum_dex::state::OpenOrders;
+use anchor_spl::dex::{self, CloseOpenOrders, InitOpenOrders};
+use anchor_spl::token::{self, CloseAccount, Token, TokenAccount, Transfer};
+
+use crate::instructions::deposit_withdraw::*;
+use crate::instructions::rebalance::*;
+use crate::{
+    constants::{
+        GLOBAL_CONFIG_STR, OPEN_ORDERS_A_STR, OPEN_ORDERS_B_STR, PORTFOLIO_INFO_STR,
+        REBALANCE_HISTORY_STR, VAULT_SIGNER_STR,
+    },
+    errors::ErrorCode,
+    instructions::{
+        fees::FeeSettlement,
+        swap::{is_valid_markets, OpenOrdersMarkets},
+    },
+    state::{GlobalConfig, PortfolioInfo, RebalanceHistory},
+    utils::OrderbookClient,
+};
+
+/// Creates a ready to rebalance portfolio in one instruction: its vaults and
+/// `PortfolioInfo`, funded with the owner's tokens as `deposit` does, and an
+/// open orders account on each of its approved markets.
+pub fn create_portfolio<'info>(
+    ctx: Context<'_, '_, '_, 'info, CreatePortfolio<'info>>,
+    token_a_percentage: u16,
+    token_b_percentage: u16,
+    pc_percentage: u16,
+) -> Result<PortfolioBalances> {
+    let vault_signer_bump = ctx.bumps["vault_signer"];
+    let rebalance_history_bump = ctx.bumps["rebalance_history"];
+    let balances = ctx.accounts.deposit.open_portfolio(
+        token_a_percentage,
+        token_b_percentage,
+        pc_percentage,
+        vault_signer_bump,
+        rebalance_history_bump,
+    )?;
+
+    let deposit = &ctx.accounts.deposit;
+    let pc_mint = deposit.pc_mint.key();
+    deposit.config.validate_market(
+        deposit.token_a_mint.key(),
+        pc_mint,
+        ctx.accounts.market_a.key(),
+    )?;
+    deposit.config.validate_market(
+        deposit.token_b_mint.key(),
+        pc_mint,
+        ctx.accounts.market_b.key(),
+    )?;
+
+    let portfolio_info_key = deposit.portfolio_info.key();
+    //Get PDA signer seed of vault owner
+    let pda_seeds = &[
+        VAULT_SIGNER_STR.as_bytes(),
+        portfolio_info_key.as_ref(),
+        &[vault_signer_bump],
+    ];
+    dex::init_open_orders(
+        ctx.accounts
+            .init_open_orders_a_context()
+            .with_signer(&[pda_seeds.as_ref()]),
+    )?;
+    dex::init_open_orders(
+        ctx.accounts
+            .init_open_orders_b_context()
+            .with_signer(&[pda_seeds.as_ref()]),
+    )?;
+
+    let portfolio_info = &mut ctx.accounts.deposit.portfolio_info;
+    portfolio_info.token_a_open_orders = ctx.accounts.open_orders_a.key();
+    portfolio_info.token_b_open_orders = ctx.accounts.open_orders_b.key();
+    portfolio_info.open_orders_a_bump = ctx.bumps["open_orders_a"];
+    portfolio_info.open_orders_b_bump = ctx.bumps["open_orders_b"];
+    Ok(balances)
+}
+
+#[derive(Accounts)]
+#[instruction(
+    token_a_percentage: u16,
+    token_b_percentage: u16,
+    pc_percentage: u16,
+)]
+pub struct CreatePortfolio<'info> {
+    pub deposit: Deposit<'info>,
+    /// CHECK: Initialized by the dex below.
+    #[account(
+        init,
+        seeds = [
+            OPEN_ORDERS_A_STR.as_ref(),
+            deposit.portfolio_info.key().as_ref()
+        ],
+        bump,
+        payer = deposit.payer,
+        owner = dex::ID,
+        space = std::mem::size_of::<OpenOrders>() + 12,
+    )]
+    pub open_orders_a: AccountInfo<'info>,
+    /// CHECK: Initialized by the dex below.
+    #[account(
+        init,
+        seeds = [
+            OPEN_ORDERS_B_STR.as_ref(),
+            deposit.portfolio_info.key().as_ref()
+        ],
+        bump,
+        payer = deposit.payer,
+        owner = dex::ID,
+        space = std::mem::size_of::<OpenOrders>() + 12,
+    )]
+    pub open_orders_b: AccountInfo<'info>,
+    /// CHECK: Must be the token a market approved in the global config.
+    pub market_a: AccountInfo<'info>,
+    /// CHECK: Must be the token b market approved in the global config.
+    pub market_b: AccountInfo<'info>,
+    /// CHECK: Only the Serum dex is accepted.
+    #[account(address = dex::ID)]
+    pub dex_program: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> CreatePortfolio<'info> {
+    fn init_open_orders_a_context(&self) -> CpiContext<'_, '_, '_, 'info, InitOpenOrders<'info>> {
+        CpiContext::new(
+            self.dex_program.clone(),
+            InitOpenOrders {
+                open_orders: self.open_orders_a.clone(),
+                authority: self.deposit.vault_signer.clone(),
+                market: self.market_a.clone(),
+                rent: self.deposit.rent.to_account_info(),
+            },
+        )
+    }
+    fn init_open_orders_b_context(&self) -> CpiContext<'_, '_, '_, 'info, InitOpenOrders<'info>> {
+        CpiContext::new(
+            self.dex_program.clone(),
+            InitOpenOrders {
+                open_orders: self.open_orders_b.clone(),
+                authority: self.deposit.vault_signer.clone(),
+                market: self.market_b.clone(),
+                rent: self.deposit.rent.to_account_info(),
+            },
+        )
+    }
+}
+
+/// Tears a portfolio down in one instruction: cancels and settles its orders on
+/// both markets, closes its open orders accounts, pays the fee recipient
+/// whatever fees are owed, hands every vault balance back to the owner, and
+/// closes the vaults, `PortfolioInfo` and its rebalance history, returning all
+/// of their rent to the owner.
+///
+/// The markets are passed as remaining accounts, and only needed when the
+/// portfolio has open orders accounts. Sweeping them stops while withdrawals
+/// are paused, as it does in `withdraw`. Once `withdraw` has closed the
+/// vaults, whatever the orders release goes straight to the owner's token
+/// accounts and only the accounts left are closed.
+///
+/// Orders filled or cancelled but not yet processed by a market's event crank
+/// still hold funds in its open orders account, which the dex won't close
+/// until the crank has run.
+pub fn close_portfolio<'info>(
+    ctx: Context<'_, '_, '_, 'info, ClosePortfolio<'info>>,
+) -> Result<()> {
+    let portfolio_info_key = ctx.accounts.portfolio_info.key();
+    //Get PDA signer seed of vault owner
+    let pda_seeds = &[
+        VAULT_SIGNER_STR.as_bytes(),
+        portfolio_info_key.as_ref(),
+        &[ctx.accounts.portfolio_info.vault_signer_bump],
+    ];
+    let vaults_open = !ctx.accounts.pc_vault.data_is_empty();
+
+    if ctx.accounts.portfolio_info.has_open_orders() {
+        require!(
+            !ctx.accounts.config.pause_flags.withdraw,
+            ErrorCode::ProgramPaused
+        );
+        let mut remaining_accounts = ctx.remaining_accounts;
+        let markets = OpenOrdersMarkets::try_accounts(
+            ctx.program_id,
+            &mut remaining_accounts,
+            &[],
+            &mut BTreeMap::new(),
+            &mut BTreeSet::new(),
+        )?;
+        is_valid_markets(
+            &ctx.accounts.portfolio_info,
+            &markets.token_a_market,
+            &markets.token_b_market,
+        )?;
+        for (mut market, user_coin_wallet) in [
+            (
+                markets.token_a_market,
+                ctx.accounts.user_token_a_account.to_account_info(),
+            ),
+            (
+                markets.token_b_market,
+                ctx.accounts.user_token_b_account.to_account_info(),
+            ),
+        ] {
+            let pc_wallet = if vaults_open {
+                ctx.accounts.pc_vault.to_account_info()
+            } else {
+                market.coin_wallet = user_coin_wallet;
+                ctx.accounts.user_pc_account.to_account_info()
+            };
+            let orderbook =
+                ctx.accounts
+                    .orderbook(market.clone(), markets.dex_program.clone(), pc_wallet);
+            orderbook.cancel_all()?;
+            orderbook.settle(None)?;
+            dex::close_open_orders(
+                ctx.accounts
+                    .close_open_orders_context(market, markets.dex_program.clone())
+                    .with_signer(&[pda_seeds.as_ref()]),
+            )?;
+        }
+    }
+
+    let mut token_a_amount = 0;
+    let mut token_b_amount = 0;
+    let mut pc_amount = 0;
+    if vaults_open {
+        // Fees owed are paid before the owner takes the rest.
+        ctx.accounts.fee_settlement().settle(
+            &mut ctx.accounts.portfolio_info,
+            &ctx.accounts.token_a_pyth_price,
+            &ctx.accounts.token_b_pyth_price,
+        )?;
+        token_a_amount = token::accessor::amount(&ctx.accounts.token_a_vault)?;
+        token_b_amount = token::accessor::amount(&ctx.accounts.token_b_vault)?;
+        pc_amount = token::accessor::amount(&ctx.accounts.pc_vault)?;
+
+        let accounts = &ctx.accounts;
+        for (vault, destination, amount) in [
+            (
+                &accounts.token_a_vault,
+                accounts.user_token_a_account.to_account_info(),
+                token_a_amount,
+            ),
+            (
+                &accounts.token_b_vault,
+                accounts.user_token_b_account.to_account_info(),
+                token_b_amount,
+            ),
+            (
+                &accounts.pc_vault,
+                accounts.user_pc_account.to_account_info(),
+                pc_amount,
+            ),
+        ] {
+            token::transfer(
+                accounts
+                    .transfer_from_vault(vault, destination)
+                    .with_signer(&[pda_seeds.as_ref()]),
+                amount,
+            )?;
+            token::close_account(
+                accounts
+                    .close_vault_context(vault)
+                    .with_signer(&[pda_seeds.as_ref()]),
+            )?;
+        }
+    }
+
+    emit!(PortfolioClosed {
+        portfolio_info: portfolio_info_key,
+        owner: ctx.accounts.user.key(),
+        token_a_amount,
+        token_b_amount,
+        pc_amount,
+    });
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct ClosePortfolio<'info> {
+    /// CHECK: The portfolio's vault, which `withdraw` may have closed already.
+    #[account(
+        mut,
+        address = portfolio_info.token_a_vault @ ErrorCode::VaultMismatch
+    )]
+    pub token_a_vault: AccountInfo<'info>,
+    /// CHECK: The portfolio's vault, which `withdraw` may have closed already.
+    #[account(
+        mut,
+        address = portfolio_info.token_b_vault @ ErrorCode::VaultMismatch
+    )]
+    pub token_b_vault: AccountInfo<'info>,
+    /// CHECK: The portfolio's vault, which `withdraw` may have closed already.
+    #[account(
+        mut,
+        address = portfolio_info.pc_vault @ ErrorCode::VaultMismatch
+    )]
+    pub pc_vault: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = user_token_a_account.mint == portfolio_info.token_a_mint @ ErrorCode::MarketMintMismatch,
+        token::authority = user,
+    )]
+    pub user_token_a_account: Box<Account<'info, TokenAccount>>,
+    #[account(
+        mut,
+        constraint = user_token_b_account.mint == portfolio_info.token_b_mint @ ErrorCode::MarketMintMismatch,
+        token::authority = user,
+    )]
+    pub user_token_b_account: Box<Account<'info, TokenAccount>>,
+    #[account(
+        mut,
+        constraint = user_pc_account.mint == portfolio_info.pc_mint @ ErrorCode::MarketMintMismatch,
+        token::authority = user,
+    )]
+    pub user_pc_account: Box<Account<'info, TokenAccount>>,
+    /// CHECK: Must be the portfolio's token a price feed.
+    #[account(address = portfolio_info.token_a_price_feed @ ErrorCode::PriceFeedMismatch)]
+    pub token_a_pyth_price: AccountInfo<'info>,
+    /// CHECK: Must be the portfolio's token b price feed.
+    #[account(address = portfolio_info.token_b_price_feed @ ErrorCode::PriceFeedMismatch)]
+    pub token_b_pyth_price: AccountInfo<'info>,
+    // The fee recipient's accounts fees owed are settled into. Any account of
+    // the right mint will do for a portfolio that charges no fees.
+    #[account(
+        mut,
+        constraint = fee_token_a_wallet.mint == portfolio_info.token_a_mint @ ErrorCode::FeeRecipientMismatch,
+        constraint = !portfolio_info.charges_fees() || fee_token_a_wallet.owner == portfolio_info.fee_recipient @ ErrorCode::FeeRecipientMismatch,
+    )]
+    pub fee_token_a_wallet: Box<Account<'info, TokenAccount>>,
+    #[account(
+        mut,
+        constraint = fee_token_b_wallet.mint == portfolio_info.token_b_mint @ ErrorCode::FeeRecipientMismatch,
+        constraint = !portfolio_info.charges_fees() || fee_token_b_wallet.owner == portfolio_info.fee_recipient @ ErrorCode::FeeRecipientMismatch,
+    )]
+    pub fee_token_b_wallet: Box<Account<'info, TokenAccount>>,
+    #[account(
+        mut,
+        constraint = fee_pc_wallet.mint == portfolio_info.pc_mint @ ErrorCode::FeeRecipientMismatch,
+        constraint = !portfolio_info.charges_fees() || fee_pc_wallet.owner == portfolio_info.fee_recipient @ ErrorCode::FeeRecipientMismatch,
+    )]
+    pub fee_pc_wallet: Box<Account<'info, TokenAccount>>,
+    /// CHECK: This is the vault signer Acct
+    #[account(
+        seeds = [VAULT_SIGNER_STR.as_bytes(), portfolio_info.key().as_ref()],
+        bump = portfolio_info.vault_signer_bump,
+    )]
+    pub vault_signer: AccountInfo<'info>,
+    #[account(
+        mut,
+        seeds = [PORTFOLIO_INFO_STR.as_bytes(), portfolio_info.creator.as_ref()],
+        bump,
+        close = user,
+    )]
+    pub portfolio_info: Box<Account<'info, PortfolioInfo>>,
+    #[account(
+        mut,
+        seeds = [REBALANCE_HISTORY_STR.as_bytes(), portfolio_info.key().as_ref()],
+        bump = rebalance_history.bump,
+        close = user,
+    )]
+    pub rebalance_history: Box<Account<'info, RebalanceHistory>>,
+    #[account(
+        seeds = [GLOBAL_CONFIG_STR.as_bytes()],
+        bump = config.bump,
+    )]
+    pub config: Box<Account<'info, GlobalConfig>>,
+    // The portfolio's owner, who receives its tokens and rent.
+    #[account(
+        mut,
+        constraint = user.key() == portfolio_info.owner @ ErrorCode::OwnerMismatch
+    )]
+    pub user: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+impl<'info> ClosePortfolio<'info> {
+    fn fee_settlement(&self) -> FeeSettlement<'info> {
+        FeeSettlement {
+            token_a_vault: self.token_a_vault.clone(),
+            token_b_vault: self.token_b_vault.clone(),
+            pc_vault: self.pc_vault.clone(),
+            fee_token_a_wallet: self.fee_token_a_wallet.to_account_info(),
+            fee_token_b_wallet: self.fee_token_b_wallet.to_account_info(),
+            fee_pc_wallet: self.fee_pc_wallet.to_account_info(),
+            vault_signer: self.vault_signer.clone(),
+            token_program: self.token_program.to_account_info(),
+        }
+    }
+
+    fn orderbook(
+        &self,
+        market: MarketAccounts<'info>,
+        dex_program: AccountInfo<'info>,
+        pc_wallet: AccountInfo<'info>,
+    ) -> OrderbookClient<'info> {
+        OrderbookClient {
+            market,
+            authority: self.vault_signer.clone(),
+            pc_wallet,
+            dex_program,
+            token_program: self.token_program.to_account_info().clone(),
+            rent: self.rent.to_account_info().clone(),
+            portfolio_info: self.portfolio_info.clone(),
+        }
+    }
+
+    fn close_open_orders_context(
+        &self,
+        market: MarketAccounts<'info>,
+        dex_program: AccountInfo<'info>,
+    ) -> CpiContext<'_, '_, '_, 'info, CloseOpenOrders<'info>> {
+        CpiContext::new(
+            dex_program,
+            CloseOpenOrders {
+                open_orders: market.open_orders,
+                authority: self.vault_signer.clone(),
+                destination: self.user.to_account_info().clone(),
+                market: market.market,
+            },
+        )
+    }
+
+    fn transfer_from_vault(
+        &self,
+        vault: &AccountInfo<'info>,
+        destination: AccountInfo<'info>,
+    ) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let transfer_acct = Transfer {
+            from: vault.clone(),
+            to: destination,
+            authority: self.vault_signer.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info(), transfer_acct)
+    }
+
+    fn close_vault_context(
+        &self,
+        vault: &AccountInfo<'info>,
+    ) -> CpiContext<'_, '_, '_, 'info, CloseAccount<'info>> {
+        let close_accounts = CloseAccount {
+            account: vault.clone(),
+            destination: self.user.to_account_info().clone(),
+            authority: self.vault_signer.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info().clone(), close_accounts)
+    }
+}
+
+// Amounts are what was handed back to the owner, in native units of each mint.
+#[event]
+pub struct PortfolioClosed {
+    portfolio_info: Pubkey,
+    owner: Pubkey,
+    token_a_amount: u64,
+    token_b_amount: u64,
+    pc_amount: u64,
+}
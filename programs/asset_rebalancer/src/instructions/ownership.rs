@@ -0,0 +1,73 @@
+use anchor_lang::prelude::*;
+
+use crate::{constants::PORTFOLIO_INFO_STR, errors::ErrorCode, state::PortfolioInfo};
+
+/// Proposes `new_owner` as the portfolio's owner, which only takes effect once
+/// they accept. Proposing the default pubkey cancels a pending transfer.
+pub fn transfer_ownership(ctx: Context<OwnerUpdate>, new_owner: Pubkey) -> Result<()> {
+    let portfolio_info = &mut ctx.accounts.portfolio_info;
+    portfolio_info.pending_owner = new_owner;
+    emit!(OwnershipTransferProposed {
+        portfolio_info: portfolio_info.key(),
+        owner: portfolio_info.owner,
+        pending_owner: new_owner,
+    });
+    Ok(())
+}
+
+/// Makes the pending owner the portfolio's owner.
+pub fn accept_ownership(ctx: Context<AcceptOwnership>) -> Result<()> {
+    let portfolio_info = &mut ctx.accounts.portfolio_info;
+    let previous_owner = portfolio_info.owner;
+    portfolio_info.owner = portfolio_info.pending_owner;
+    portfolio_info.pending_owner = Pubkey::default();
+    emit!(OwnershipTransferred {
+        portfolio_info: portfolio_info.key(),
+        previous_owner,
+        owner: portfolio_info.owner,
+    });
+    Ok(())
+}
+
+// A portfolio setting only its owner can change.
+#[derive(Accounts)]
+pub struct OwnerUpdate<'info> {
+    #[account(
+        mut,
+        seeds = [PORTFOLIO_INFO_STR.as_bytes(), portfolio_info.creator.as_ref()],
+        bump,
+    )]
+    pub portfolio_info: Box<Account<'info, PortfolioInfo>>,
+    #[account(
+        constraint = user.key() == portfolio_info.owner @ ErrorCode::OwnerMismatch
+    )]
+    pub user: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptOwnership<'info> {
+    #[account(
+        mut,
+        seeds = [PORTFOLIO_INFO_STR.as_bytes(), portfolio_info.creator.as_ref()],
+        bump,
+    )]
+    pub portfolio_info: Box<Account<'info, PortfolioInfo>>,
+    #[account(
+        constraint = new_owner.key() == portfolio_info.pending_owner @ ErrorCode::NotPendingOwner
+    )]
+    pub new_owner: Signer<'info>,
+}
+
+#[event]
+pub struct OwnershipTransferProposed {
+    portfolio_info: Pubkey,
+    owner: Pubkey,
+    pending_owner: Pubkey,
+}
+
+#[event]
+pub struct OwnershipTransferred {
+    portfolio_info: Pubkey,
+    previous_owner: Pubkey,
+    owner: Pubkey,
+}
@@ -2,33 +2,58 @@ use anchor_lang::prelude::*;
 
 use crate::{
     errors::ErrorCode,
-    utils::{empty, swap_transitive, ExchangeRate, OrderbookClient, SwapDirection},
+    utils::{
+        buy_with_quote, calculate_amount_to_sell, calculate_cost_basis_after_trade,
+        calculate_keeper_fee, calculate_pnl, calculate_quote_amount, calculate_target_worth,
+        calculate_vault_worth, coin_lot_size, empty, is_negligible_worth, sell_for_quote,
+        swap_transitive, ExchangeRate, Holding, OrderbookClient, SwapDirection, VaultValuation,
+    },
+};
+use anchor_spl::{
+    dex,
+    token::{self, Token, TokenAccount},
 };
-use anchor_spl::token::{self, Token, TokenAccount};
 use pyth_sdk_solana::{load_price_feed_from_account_info, Price, PriceFeed};
 
 use crate::{
-    constants::{PORTFOLIO_INFO_STR, VAULT_SIGNER_STR},
-    state::PortfolioInfo,
+    constants::{GLOBAL_CONFIG_STR, PORTFOLIO_INFO_STR, REBALANCE_HISTORY_STR, VAULT_SIGNER_STR},
+    instructions::swap::is_valid_markets,
+    state::{Decimal, GlobalConfig, PortfolioInfo, RebalanceHistory, RebalanceRecord},
     utils::calculate_assets_percentage_worth_in_vault,
 };
 
 pub fn refresh_prices(ctx: Context<RefreshPriceContext>) -> Result<()> {
     //get prices
-    let token_a_price_feed: PriceFeed =
-        load_price_feed_from_account_info(&ctx.accounts.token_a_pyth_price).unwrap();
-    let token_b_price_feed: PriceFeed =
-        load_price_feed_from_account_info(&ctx.accounts.token_b_pyth_price).unwrap();
-    let token_a_price: Price = token_a_price_feed.get_current_price().unwrap();
-    let token_b_price: Price = token_b_price_feed.get_current_price().unwrap();
+    let token_a_price: Price = load_current_price(&ctx.accounts.token_a_pyth_price)?;
+    let token_b_price: Price = load_current_price(&ctx.accounts.token_b_pyth_price)?;
 
     ctx.accounts.portfolio_info.token_a_price = token_a_price.price.into();
     ctx.accounts.portfolio_info.token_b_price = token_b_price.price.into();
+    ctx.accounts.portfolio_info.token_a_price_expo = token_a_price.expo;
+    ctx.accounts.portfolio_info.token_b_price_expo = token_b_price.expo;
+    ctx.accounts.portfolio_info.last_update_unix = Clock::get()?.unix_timestamp;
     Ok(())
 }
-pub fn rebalance_assets<'info>(ctx: Context<'_, '_, '_, 'info, Rebalance<'info>>) -> Result<()> {
+#[access_control(is_valid_rebalance(&ctx))]
+pub fn rebalance_assets<'info>(
+    mut ctx: Context<'_, '_, '_, 'info, Rebalance<'info>>,
+) -> Result<PortfolioBalances> {
+    let clock = Clock::get()?;
+    if ctx
+        .accounts
+        .portfolio_info
+        .apply_pending_allocation(clock.unix_timestamp)
+    {
+        msg!("pending allocation applied");
+    }
+    if ctx
+        .accounts
+        .portfolio_info
+        .apply_glide_path(clock.unix_timestamp)?
+    {
+        msg!("target allocation moved along the glide path");
+    }
     let portfolio_info = ctx.accounts.portfolio_info.clone();
-    let clock = Clock::get().unwrap();
     // Ensure price is recent(within the last minute)
     require!(
         portfolio_info.last_update_unix + 60i64 > clock.unix_timestamp,
@@ -36,194 +61,782 @@ pub fn rebalance_assets<'info>(ctx: Context<'_, '_, '_, 'info, Rebalance<'info>>
     );
     let token_a_vault = ctx.accounts.token_a_market.coin_wallet.clone();
     let token_b_vault = ctx.accounts.token_b_market.coin_wallet.clone();
+    let pc_vault = ctx.accounts.pc_wallet.to_account_info();
+    let token_a_amount_before = token::accessor::amount(&token_a_vault)?;
+    let token_b_amount_before = token::accessor::amount(&token_b_vault)?;
 
+    let valuation = vault_worth(&portfolio_info, &token_a_vault, &token_b_vault, &pc_vault)?;
+    let token_a_worth = valuation.token_a_worth;
+    let token_b_worth = valuation.token_b_worth;
+    let pc_worth = valuation.pc_worth;
+    let total_vault_worth = valuation.total_worth;
 
-    let vault_a_balance = token::accessor::amount(&token_a_vault)
-        .unwrap()
-        .checked_div(
-            10u64
-                .checked_pow(portfolio_info.token_a_decimals.into())
-                .unwrap(),
-        )
-        .unwrap();
-
-    let vault_b_balance = token::accessor::amount(&token_b_vault)
-        .unwrap()
-        .checked_div(
-            10u64
-                .checked_pow(portfolio_info.token_b_decimals.into())
-                .unwrap(),
-        )
-        .unwrap();
+    // A vault holding only dust is worth too little to weigh its assets.
+    if is_negligible_worth(total_vault_worth)? {
+        msg!("vault is empty, nothing to rebalance");
+        emit!(RebalanceSkipped {
+            portfolio_info: portfolio_info.key(),
+            mint: Pubkey::default(),
+            amount: 0,
+            reason: SkipReason::EmptyVault,
+        });
+        return portfolio_balances(&portfolio_info, &token_a_vault, &token_b_vault, &pc_vault);
+    }
 
-    let current_token_a_percentage = calculate_assets_percentage_worth_in_vault(
-        vault_a_balance, //actual amount (eliminating decimals -- so calculations can be balanced )
-        portfolio_info.token_a_price as u64,
-        vault_b_balance,
-        ctx.accounts.portfolio_info.token_b_price as u64,
-    );
-    let current_token_b_percentage = calculate_assets_percentage_worth_in_vault(
-        vault_b_balance,
-        ctx.accounts.portfolio_info.token_b_price as u64,
-        vault_a_balance,
-        portfolio_info.token_a_price as u64,
+    let current_token_a_percentage =
+        calculate_assets_percentage_worth_in_vault(token_a_worth, total_vault_worth)?;
+    let current_token_b_percentage =
+        calculate_assets_percentage_worth_in_vault(token_b_worth, total_vault_worth)?;
+    let current_pc_percentage =
+        calculate_assets_percentage_worth_in_vault(pc_worth, total_vault_worth)?;
+
+    // Quote currency held above (or below) the cash target, e.g. spill from
+    // earlier swaps. Anything above the target and the fees owed is
+    // redeployed into the underweight asset.
+    let target_pc_worth = calculate_target_worth(total_vault_worth, portfolio_info.pc_percentage)?;
+    let idle_quote = token::accessor::amount(&pc_vault)?.saturating_sub(
+        calculate_quote_amount(target_pc_worth, portfolio_info.pc_decimals)?
+            .saturating_add(portfolio_info.unpaid_fees),
     );
 
-    let portfolio_info = ctx.accounts.portfolio_info.clone();
+    msg!("current a percentage: {}", current_token_a_percentage);
+    msg!("current b percentage: {}", current_token_b_percentage);
+    msg!("current quote percentage: {}", current_pc_percentage);
 
-    if current_token_a_percentage == portfolio_info.token_a_percentage {
-        msg!(
-            "portfolio is balanced token a percent:{}, token b percent: {}",
+    // Fees owed but not yet covered by the quote sleeve are always raised.
+    if !pc_worth.is_negative()
+        && portfolio_info.within_rebalance_band(
             current_token_a_percentage,
-            current_token_b_percentage
-        );
-    } else if current_token_a_percentage > portfolio_info.token_a_percentage {
-        // sell a, buy b
-        msg!("A is the outperforming asset");
-        msg!(
-            "expected a percentage: {}",
-            portfolio_info.token_a_percentage
-        );
-        msg!("current a percentage: {}", current_token_a_percentage);
-        msg!("current b percentage: {}", current_token_b_percentage);
-        msg!(
-            "sell {} of A to buy B",
-            (current_token_a_percentage - portfolio_info.token_a_percentage)
-        );
-        let percentage_to_sell = current_token_a_percentage - portfolio_info.token_a_percentage;
-
-        let amount_to_swap = token::accessor::amount(&token_a_vault)
-            .unwrap()
-            .checked_mul(percentage_to_sell as u64)
-            .unwrap()
-            .checked_div(1000u64)
-            .unwrap();
-
-        msg!("amount of A to swap: {}", amount_to_swap);
-        msg!(
-            "current amount of A: {}",
-            token::accessor::amount(&token_a_vault).unwrap()
-        );
-
-        swap_transitive(
-            ctx,
-            SwapDirection::AB,
-            amount_to_swap,
-            ExchangeRate {
-                rate: 1,
-                from_decimals: portfolio_info.token_a_decimals,
-                quote_decimals: portfolio_info.pc_decimals,
-                strict: false,
-            },
-        )?;
-    } else {
-        // sell b, buy a
-        msg!("B is the outperforming asset");
-        msg!(
-            "expected a percentage: {}",
-            portfolio_info.token_b_percentage
-        );
-        msg!("current a percentage: {}", current_token_a_percentage);
-        msg!("current b percentage: {}", current_token_b_percentage);
-        msg!(
-            "sell {} of B to buy A",
-            (current_token_b_percentage - portfolio_info.token_b_percentage)
-        );
-        let percentage_to_sell = current_token_b_percentage - portfolio_info.token_b_percentage;
-
-        let amount_to_swap = token::accessor::amount(&token_b_vault)
-            .unwrap()
-            .checked_mul(percentage_to_sell as u64)
-            .unwrap()
-            .checked_div(1000u64)
-            .unwrap();
-
-        msg!("amount of B to swap: {}", amount_to_swap);
-        msg!(
-            "current amount of B: {}",
-            token::accessor::amount(&token_b_vault).unwrap()
-        );
-
-        swap_transitive(
-            ctx,
-            SwapDirection::BA,
-            amount_to_swap,
-            ExchangeRate {
-                rate: 1,
-                from_decimals: portfolio_info.token_b_decimals,
-                quote_decimals: portfolio_info.pc_decimals,
-                strict: false,
-            },
-        )?;
-    }
-    let vault_a_balance = token::accessor::amount(&token_a_vault)
-        .unwrap()
-        .checked_div(
-            10u64
-                .checked_pow(portfolio_info.token_a_decimals.into())
-                .unwrap(),
-        )
-        .unwrap();
-
-    let vault_b_balance = token::accessor::amount(&token_b_vault)
-        .unwrap()
-        .checked_div(
-            10u64
-                .checked_pow(portfolio_info.token_b_decimals.into())
-                .unwrap(),
+            current_token_b_percentage,
+            current_pc_percentage,
         )
-        .unwrap();
+    {
+        msg!("portfolio is within its rebalance band");
+        emit!(RebalanceSkipped {
+            portfolio_info: portfolio_info.key(),
+            mint: Pubkey::default(),
+            amount: 0,
+            reason: SkipReason::WithinBand,
+        });
+        return portfolio_balances(&portfolio_info, &token_a_vault, &token_b_vault, &pc_vault);
+    }
+
+    let token_a_overweight = current_token_a_percentage > portfolio_info.token_a_percentage;
+    let token_b_overweight = current_token_b_percentage > portfolio_info.token_b_percentage;
+    let pc_underweight = pc_worth < target_pc_worth;
+    // Quote currency the rebalance moved, which the keeper fee is a cut of.
+    // Each trade's notional is counted once: buys funded by a sale in the
+    // same rebalance aren't added again.
+    let mut traded_quote: u64 = 0;
+    // Quote currency left over from transitive swaps.
+    let mut spill_amount: u64 = 0;
+
+    match (token_a_overweight, token_b_overweight) {
+        (true, true) => {
+            // Only the quote currency is underweight, so both assets are
+            // sold into it.
+            msg!("quote currency is underweight, sell A and B");
+            let amount_of_a = amount_to_sell(
+                &token_a_vault,
+                token_a_worth,
+                total_vault_worth,
+                portfolio_info.token_a_percentage,
+            )?;
+            let amount_of_b = amount_to_sell(
+                &token_b_vault,
+                token_b_worth,
+                total_vault_worth,
+                portfolio_info.token_b_percentage,
+            )?;
+            msg!("amount of A to sell: {}", amount_of_a);
+            msg!("amount of B to sell: {}", amount_of_b);
+            if can_sell(ctx.accounts, &SwapDirection::AB, amount_of_a)? {
+                let sold = sell_for_quote(&mut ctx, SwapDirection::AB, amount_of_a)?;
+                traded_quote = traded_quote.saturating_add(sold);
+            }
+            if can_sell(ctx.accounts, &SwapDirection::BA, amount_of_b)? {
+                let sold = sell_for_quote(&mut ctx, SwapDirection::BA, amount_of_b)?;
+                traded_quote = traded_quote.saturating_add(sold);
+            }
+        }
+        (true, false) => {
+            // sell a, buy b
+            msg!("A is the outperforming asset");
+            msg!(
+                "expected a percentage: {}",
+                portfolio_info.token_a_percentage
+            );
+            msg!(
+                "sell {} bps of A to buy B",
+                (current_token_a_percentage - portfolio_info.token_a_percentage)
+            );
+            let amount_to_swap = amount_to_sell(
+                &token_a_vault,
+                token_a_worth,
+                total_vault_worth,
+                portfolio_info.token_a_percentage,
+            )?;
+
+            msg!("amount of A to swap: {}", amount_to_swap);
+            msg!(
+                "current amount of A: {}",
+                token::accessor::amount(&token_a_vault)?
+            );
+
+            if pc_underweight {
+                // Part of the proceeds tops up the cash sleeve, so only B's
+                // shortfall is bought back.
+                let token_b_shortfall = shortfall_quote(
+                    total_vault_worth,
+                    token_b_worth,
+                    portfolio_info.token_b_percentage,
+                    portfolio_info.pc_decimals,
+                )?;
+                if can_sell(ctx.accounts, &SwapDirection::AB, amount_to_swap)? {
+                    let sold = sell_for_quote(&mut ctx, SwapDirection::AB, amount_to_swap)?;
+                    traded_quote = traded_quote.saturating_add(sold);
+                    let quote_available = token::accessor::amount(&pc_vault)?
+                        .saturating_sub(portfolio_info.unpaid_fees);
+                    let quote_to_spend = token_b_shortfall.min(quote_available);
+                    if can_buy(ctx.accounts, &SwapDirection::AB, quote_to_spend)? {
+                        buy_with_quote(&mut ctx, SwapDirection::AB, quote_to_spend)?;
+                    }
+                }
+            } else if can_sell(ctx.accounts, &SwapDirection::AB, amount_to_swap)? {
+                let (sold, spill) = swap_transitive(
+                    &mut ctx,
+                    SwapDirection::AB,
+                    amount_to_swap,
+                    idle_quote,
+                    ExchangeRate {
+                        rate: 1,
+                        from_decimals: portfolio_info.token_a_decimals,
+                        quote_decimals: portfolio_info.pc_decimals,
+                        strict: false,
+                    },
+                )?;
+                traded_quote = traded_quote.saturating_add(sold);
+                spill_amount = spill_amount.saturating_add(spill);
+            }
+        }
+        (false, true) => {
+            // sell b, buy a
+            msg!("B is the outperforming asset");
+            msg!(
+                "expected b percentage: {}",
+                portfolio_info.token_b_percentage
+            );
+            msg!(
+                "sell {} bps of B to buy A",
+                (current_token_b_percentage - portfolio_info.token_b_percentage)
+            );
+            let amount_to_swap = amount_to_sell(
+                &token_b_vault,
+                token_b_worth,
+                total_vault_worth,
+                portfolio_info.token_b_percentage,
+            )?;
+
+            msg!("amount of B to swap: {}", amount_to_swap);
+            msg!(
+                "current amount of B: {}",
+                token::accessor::amount(&token_b_vault)?
+            );
+
+            if pc_underweight {
+                let token_a_shortfall = shortfall_quote(
+                    total_vault_worth,
+                    token_a_worth,
+                    portfolio_info.token_a_percentage,
+                    portfolio_info.pc_decimals,
+                )?;
+                if can_sell(ctx.accounts, &SwapDirection::BA, amount_to_swap)? {
+                    let sold = sell_for_quote(&mut ctx, SwapDirection::BA, amount_to_swap)?;
+                    traded_quote = traded_quote.saturating_add(sold);
+                    let quote_available = token::accessor::amount(&pc_vault)?
+                        .saturating_sub(portfolio_info.unpaid_fees);
+                    let quote_to_spend = token_a_shortfall.min(quote_available);
+                    if can_buy(ctx.accounts, &SwapDirection::BA, quote_to_spend)? {
+                        buy_with_quote(&mut ctx, SwapDirection::BA, quote_to_spend)?;
+                    }
+                }
+            } else if can_sell(ctx.accounts, &SwapDirection::BA, amount_to_swap)? {
+                let (sold, spill) = swap_transitive(
+                    &mut ctx,
+                    SwapDirection::BA,
+                    amount_to_swap,
+                    idle_quote,
+                    ExchangeRate {
+                        rate: 1,
+                        from_decimals: portfolio_info.token_b_decimals,
+                        quote_decimals: portfolio_info.pc_decimals,
+                        strict: false,
+                    },
+                )?;
+                traded_quote = traded_quote.saturating_add(sold);
+                spill_amount = spill_amount.saturating_add(spill);
+            }
+        }
+        (false, false) if current_pc_percentage > portfolio_info.pc_percentage => {
+            // Neither asset is overweight, so the quote above the cash target
+            // is split between them by how far each one is below its target.
+            msg!("quote currency is overweight");
+            let token_a_shortfall = shortfall_quote(
+                total_vault_worth,
+                token_a_worth,
+                portfolio_info.token_a_percentage,
+                portfolio_info.pc_decimals,
+            )?;
 
-    let new_token_a_worth = vault_a_balance
-        .checked_mul(portfolio_info.token_a_price as u64)
-        .unwrap();
+            let quote_for_a = token_a_shortfall.min(idle_quote);
+            let quote_for_b = idle_quote
+                .checked_sub(quote_for_a)
+                .ok_or(ErrorCode::MathOverflow)?;
 
-    let new_token_b_worth = vault_b_balance
-        .checked_mul(portfolio_info.token_b_price as u64)
-        .unwrap();
+            if quote_for_a > 0 && can_buy(ctx.accounts, &SwapDirection::BA, quote_for_a)? {
+                let spent = buy_with_quote(&mut ctx, SwapDirection::BA, quote_for_a)?;
+                traded_quote = traded_quote.saturating_add(spent);
+            }
+            if quote_for_b > 0 && can_buy(ctx.accounts, &SwapDirection::AB, quote_for_b)? {
+                let spent = buy_with_quote(&mut ctx, SwapDirection::AB, quote_for_b)?;
+                traded_quote = traded_quote.saturating_add(spent);
+            }
+        }
+        (false, false) => {
+            msg!(
+                "portfolio is balanced token a percent:{}, token b percent: {}",
+                current_token_a_percentage,
+                current_token_b_percentage
+            );
+        }
+    }
+
+    let keeper_fee = pay_keeper_fee(ctx.accounts, traded_quote)?;
+
+    let new_valuation = vault_worth(&portfolio_info, &token_a_vault, &token_b_vault, &pc_vault)?;
+    let in_quote = |worth: Decimal| calculate_quote_amount(worth, portfolio_info.pc_decimals);
 
     emit!(AssetsBalanced {
-        new_token_a_worth,
+        new_token_a_worth: in_quote(new_valuation.token_a_worth)?,
         token_a_percentage: portfolio_info.token_a_percentage,
-        new_token_b_worth,
+        new_token_b_worth: in_quote(new_valuation.token_b_worth)?,
         token_b_percentage: portfolio_info.token_b_percentage,
+        new_pc_worth: in_quote(new_valuation.pc_worth)?,
+        pc_percentage: portfolio_info.pc_percentage,
+        keeper: ctx.accounts.keeper.key(),
+        keeper_fee,
     });
+
+    let token_a_amount_after = token::accessor::amount(&token_a_vault)?;
+    let token_b_amount_after = token::accessor::amount(&token_b_vault)?;
+    book_trades(
+        &mut ctx.accounts.portfolio_info,
+        (token_a_amount_before, token_a_amount_after),
+        (token_b_amount_before, token_b_amount_after),
+    )?;
+    let new_total_worth = new_valuation.total_worth;
+    ctx.accounts.rebalance_history.push(RebalanceRecord {
+        unix_timestamp: clock.unix_timestamp,
+        token_a_price: portfolio_info.token_a_price,
+        token_a_price_expo: portfolio_info.token_a_price_expo,
+        token_b_price: portfolio_info.token_b_price,
+        token_b_price_expo: portfolio_info.token_b_price_expo,
+        token_a_weight_before: current_token_a_percentage,
+        token_b_weight_before: current_token_b_percentage,
+        pc_weight_before: current_pc_percentage,
+        token_a_weight_after: calculate_assets_percentage_worth_in_vault(
+            new_valuation.token_a_worth,
+            new_total_worth,
+        )?,
+        token_b_weight_after: calculate_assets_percentage_worth_in_vault(
+            new_valuation.token_b_worth,
+            new_total_worth,
+        )?,
+        pc_weight_after: calculate_assets_percentage_worth_in_vault(
+            new_valuation.pc_worth,
+            new_total_worth,
+        )?,
+        token_a_sold: token_a_amount_before.saturating_sub(token_a_amount_after),
+        token_a_bought: token_a_amount_after.saturating_sub(token_a_amount_before),
+        token_b_sold: token_b_amount_before.saturating_sub(token_b_amount_after),
+        token_b_bought: token_b_amount_after.saturating_sub(token_b_amount_before),
+        spill_amount,
+        keeper_fee,
+        net_asset_value: in_quote(new_total_worth)?,
+        benchmark_nav: benchmark_nav(&portfolio_info)?,
+    });
+    portfolio_balances(&portfolio_info, &token_a_vault, &token_b_vault, &pc_vault)
+}
+
+// Pays the keeper its cut of `traded_quote` out of the quote vault, never more
+// than the vault holds beyond the fees it owes. Nothing is paid for a
+// rebalance that didn't trade. Returns the fee paid.
+fn pay_keeper_fee(accounts: &Rebalance, traded_quote: u64) -> Result<u64> {
+    let portfolio_info = &accounts.portfolio_info;
+    let pc_vault = accounts.pc_wallet.to_account_info();
+    let keeper_fee = calculate_keeper_fee(traded_quote, portfolio_info.keeper_fee_bps)?
+        .min(token::accessor::amount(&pc_vault)?.saturating_sub(portfolio_info.unpaid_fees));
+    if keeper_fee == 0 {
+        return Ok(0);
+    }
+
+    let portfolio_info_key = portfolio_info.key();
+    let pda_seeds = &[
+        VAULT_SIGNER_STR.as_bytes(),
+        portfolio_info_key.as_ref(),
+        &[portfolio_info.vault_signer_bump],
+    ];
+    token::transfer(
+        CpiContext::new(
+            accounts.token_program.to_account_info(),
+            token::Transfer {
+                from: pc_vault,
+                to: accounts.keeper_fee_wallet.to_account_info(),
+                authority: accounts.vault_signer.clone(),
+            },
+        )
+        .with_signer(&[pda_seeds.as_ref()]),
+        keeper_fee,
+    )?;
+    msg!("paid keeper fee: {}", keeper_fee);
+    Ok(keeper_fee)
+}
+
+// Books the net amount of each asset a rebalance traded, given as its vault
+// balance before and after, into the asset's cost basis and the portfolio's
+// realized PnL. Trades are valued at the prices the rebalance used.
+fn book_trades(
+    portfolio_info: &mut PortfolioInfo,
+    token_a_amounts: (u64, u64),
+    token_b_amounts: (u64, u64),
+) -> Result<()> {
+    let (token_a_before, token_a_after) = token_a_amounts;
+    let (token_b_before, token_b_after) = token_b_amounts;
+    let token_a_traded = calculate_quote_amount(
+        token_a_holding(portfolio_info, token_a_before.abs_diff(token_a_after))?.worth()?,
+        portfolio_info.pc_decimals,
+    )?;
+    let token_b_traded = calculate_quote_amount(
+        token_b_holding(portfolio_info, token_b_before.abs_diff(token_b_after))?.worth()?,
+        portfolio_info.pc_decimals,
+    )?;
+    let (token_a_cost_basis, token_a_pnl) = calculate_cost_basis_after_trade(
+        portfolio_info.token_a_cost_basis,
+        token_a_before,
+        token_a_after,
+        token_a_traded,
+    )?;
+    let (token_b_cost_basis, token_b_pnl) = calculate_cost_basis_after_trade(
+        portfolio_info.token_b_cost_basis,
+        token_b_before,
+        token_b_after,
+        token_b_traded,
+    )?;
+    portfolio_info.token_a_cost_basis = token_a_cost_basis;
+    portfolio_info.token_b_cost_basis = token_b_cost_basis;
+    portfolio_info.realized_pnl = portfolio_info
+        .realized_pnl
+        .checked_add(token_a_pnl)
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_add(token_b_pnl)
+        .ok_or(ErrorCode::MathOverflow)?;
     Ok(())
 }
+
+// Returns the amount of the vault's token to sell to bring its worth back
+// down to its target share of the total vault worth.
+fn amount_to_sell(
+    vault: &AccountInfo,
+    asset_worth: Decimal,
+    total_vault_worth: Decimal,
+    target_percentage: u16,
+) -> Result<u64> {
+    calculate_amount_to_sell(
+        token::accessor::amount(vault)?,
+        asset_worth,
+        total_vault_worth,
+        target_percentage,
+    )
+}
+
+// Returns the amount of quote currency needed to bring an asset worth
+// `asset_worth` up to its target percentage of `total_vault_worth`.
+fn shortfall_quote(
+    total_vault_worth: Decimal,
+    asset_worth: Decimal,
+    target_percentage: u16,
+    pc_decimals: u8,
+) -> Result<u64> {
+    let target_worth = calculate_target_worth(total_vault_worth, target_percentage)?;
+    let shortfall = target_worth
+        .checked_sub(asset_worth)
+        .ok_or(ErrorCode::MathOverflow)?
+        .max(Decimal::zero());
+    calculate_quote_amount(shortfall, pc_decimals)
+}
+
+// Returns whether selling `amount` of the *from* token of `swap_direction` is
+// worth an order, emitting `RebalanceSkipped` if it isn't.
+fn can_sell(accounts: &Rebalance, swap_direction: &SwapDirection, amount: u64) -> Result<bool> {
+    let portfolio_info = &accounts.portfolio_info;
+    let (market, mint, holding) = match swap_direction {
+        SwapDirection::AB => (
+            &accounts.token_a_market.market,
+            portfolio_info.token_a_mint,
+            token_a_holding(portfolio_info, amount)?,
+        ),
+        SwapDirection::BA => (
+            &accounts.token_b_market.market,
+            portfolio_info.token_b_mint,
+            token_b_holding(portfolio_info, amount)?,
+        ),
+    };
+    let quote_worth = calculate_quote_amount(holding.worth()?, portfolio_info.pc_decimals)?;
+
+    let skip_reason = if amount < coin_lot_size(market)? {
+        Some(SkipReason::BelowLotSize)
+    } else if quote_worth < portfolio_info.dust_threshold {
+        Some(SkipReason::BelowDustThreshold)
+    } else {
+        None
+    };
+    Ok(!skip_order(portfolio_info.key(), mint, amount, skip_reason))
+}
+
+// Returns whether spending `quote_amount` on the *to* token of
+// `swap_direction` is worth an order, emitting `RebalanceSkipped` if it isn't.
+fn can_buy(
+    accounts: &Rebalance,
+    swap_direction: &SwapDirection,
+    quote_amount: u64,
+) -> Result<bool> {
+    let portfolio_info = &accounts.portfolio_info;
+    let (market, mint) = match swap_direction {
+        SwapDirection::AB => (&accounts.token_b_market.market, portfolio_info.token_b_mint),
+        SwapDirection::BA => (&accounts.token_a_market.market, portfolio_info.token_a_mint),
+    };
+    let lot_size = coin_lot_size(market)?;
+    let lot = match swap_direction {
+        SwapDirection::AB => token_b_holding(portfolio_info, lot_size)?,
+        SwapDirection::BA => token_a_holding(portfolio_info, lot_size)?,
+    };
+    // What a single lot costs at the portfolio's last refreshed price.
+    let lot_quote = calculate_quote_amount(lot.worth()?, portfolio_info.pc_decimals)?;
+
+    let skip_reason = if quote_amount == 0 || quote_amount < lot_quote {
+        Some(SkipReason::BelowLotSize)
+    } else if quote_amount < portfolio_info.dust_threshold {
+        Some(SkipReason::BelowDustThreshold)
+    } else {
+        None
+    };
+    Ok(!skip_order(
+        portfolio_info.key(),
+        mint,
+        quote_amount,
+        skip_reason,
+    ))
+}
+
+// Emits `RebalanceSkipped` if there is a reason to skip the order. Returns
+// whether the order is skipped.
+fn skip_order(
+    portfolio_info: Pubkey,
+    mint: Pubkey,
+    amount: u64,
+    skip_reason: Option<SkipReason>,
+) -> bool {
+    match skip_reason {
+        Some(reason) => {
+            msg!("skipping order of {} for {}: {:?}", amount, mint, reason);
+            emit!(RebalanceSkipped {
+                portfolio_info,
+                mint,
+                amount,
+                reason,
+            });
+            true
+        }
+        None => false,
+    }
+}
+
+// `amount` of token a at the portfolio's last refreshed price.
+pub fn token_a_holding(portfolio_info: &PortfolioInfo, amount: u64) -> Result<Holding> {
+    Ok(Holding {
+        amount,
+        decimals: portfolio_info.token_a_decimals,
+        price: Decimal::from_mantissa(
+            portfolio_info.token_a_price,
+            portfolio_info.token_a_price_expo,
+        )
+        .ok_or(ErrorCode::MathOverflow)?,
+    })
+}
+
+// `amount` of token b at the portfolio's last refreshed price.
+pub fn token_b_holding(portfolio_info: &PortfolioInfo, amount: u64) -> Result<Holding> {
+    Ok(Holding {
+        amount,
+        decimals: portfolio_info.token_b_decimals,
+        price: Decimal::from_mantissa(
+            portfolio_info.token_b_price,
+            portfolio_info.token_b_price_expo,
+        )
+        .ok_or(ErrorCode::MathOverflow)?,
+    })
+}
+
+// Values the vault's native token a, token b and quote balances at the
+// portfolio's last refreshed prices. Fees owed to the fee recipient are set
+// aside out of the quote sleeve, which is worth less than nothing when it
+// can't cover them.
+pub fn vault_worth(
+    portfolio_info: &PortfolioInfo,
+    token_a_vault: &AccountInfo,
+    token_b_vault: &AccountInfo,
+    pc_vault: &AccountInfo,
+) -> Result<VaultValuation> {
+    let mut valuation = calculate_vault_worth(
+        &token_a_holding(portfolio_info, token::accessor::amount(token_a_vault)?)?,
+        &token_b_holding(portfolio_info, token::accessor::amount(token_b_vault)?)?,
+        &Holding::quote(
+            token::accessor::amount(pc_vault)?,
+            portfolio_info.pc_decimals,
+        ),
+    )?;
+    let unpaid_fees =
+        Holding::quote(portfolio_info.unpaid_fees, portfolio_info.pc_decimals).worth()?;
+    valuation.pc_worth = valuation
+        .pc_worth
+        .checked_sub(unpaid_fees)
+        .ok_or(ErrorCode::MathOverflow)?;
+    valuation.total_worth = valuation
+        .total_worth
+        .checked_sub(unpaid_fees)
+        .ok_or(ErrorCode::MathOverflow)?;
+    Ok(valuation)
+}
+
+// The portfolio as priced by its feeds right now, leaving the prices it
+// stores untouched.
+pub fn at_current_prices(
+    portfolio_info: &PortfolioInfo,
+    token_a_pyth_price: &AccountInfo,
+    token_b_pyth_price: &AccountInfo,
+) -> Result<PortfolioInfo> {
+    let token_a_price = load_current_price(token_a_pyth_price)?;
+    let token_b_price = load_current_price(token_b_pyth_price)?;
+    let mut priced = *portfolio_info;
+    priced.token_a_price = token_a_price.price.into();
+    priced.token_b_price = token_b_price.price.into();
+    priced.token_a_price_expo = token_a_price.expo;
+    priced.token_b_price_expo = token_b_price.expo;
+    Ok(priced)
+}
+
+// The vault's native balances and its net asset value, in native quote units,
+// at the portfolio's last refreshed prices.
+pub fn portfolio_balances(
+    portfolio_info: &PortfolioInfo,
+    token_a_vault: &AccountInfo,
+    token_b_vault: &AccountInfo,
+    pc_vault: &AccountInfo,
+) -> Result<PortfolioBalances> {
+    let valuation = vault_worth(portfolio_info, token_a_vault, token_b_vault, pc_vault)?;
+    Ok(PortfolioBalances {
+        token_a_amount: token::accessor::amount(token_a_vault)?,
+        token_b_amount: token::accessor::amount(token_b_vault)?,
+        pc_amount: token::accessor::amount(pc_vault)?,
+        net_asset_value: calculate_quote_amount(valuation.total_worth, portfolio_info.pc_decimals)?,
+    })
+}
+
+// What the amounts first deposited would be worth, in native quote units, at
+// the portfolio's last refreshed prices had they been held as they were.
+pub fn benchmark_nav(portfolio_info: &PortfolioInfo) -> Result<u64> {
+    let valuation = calculate_vault_worth(
+        &token_a_holding(portfolio_info, portfolio_info.token_a_initial_amount)?,
+        &token_b_holding(portfolio_info, portfolio_info.token_b_initial_amount)?,
+        &Holding::quote(portfolio_info.pc_initial_amount, portfolio_info.pc_decimals),
+    )?;
+    calculate_quote_amount(valuation.total_worth, portfolio_info.pc_decimals)
+}
+
+/// Returns how the portfolio has done at its last refreshed prices: its cost
+/// basis and PnL, and its net asset value against the buy-and-hold benchmark
+/// of its initial deposit.
+pub fn get_portfolio_stats(ctx: Context<ViewPortfolio>) -> Result<PortfolioStats> {
+    let portfolio_info = &ctx.accounts.portfolio_info;
+    let valuation = vault_worth(
+        portfolio_info,
+        &ctx.accounts.token_a_vault.to_account_info(),
+        &ctx.accounts.token_b_vault.to_account_info(),
+        &ctx.accounts.pc_vault.to_account_info(),
+    )?;
+    let net_asset_value =
+        calculate_quote_amount(valuation.total_worth, portfolio_info.pc_decimals)?;
+    let benchmark_nav = benchmark_nav(portfolio_info)?;
+    let tokens_worth = calculate_quote_amount(
+        valuation
+            .token_a_worth
+            .checked_add(valuation.token_b_worth)
+            .ok_or(ErrorCode::MathOverflow)?,
+        portfolio_info.pc_decimals,
+    )?;
+    let cost_basis = portfolio_info
+        .token_a_cost_basis
+        .checked_add(portfolio_info.token_b_cost_basis)
+        .ok_or(ErrorCode::MathOverflow)?;
+    Ok(PortfolioStats {
+        net_asset_value,
+        benchmark_nav,
+        rebalancing_premium: calculate_pnl(net_asset_value, benchmark_nav)?,
+        token_a_cost_basis: portfolio_info.token_a_cost_basis,
+        token_b_cost_basis: portfolio_info.token_b_cost_basis,
+        realized_pnl: portfolio_info.realized_pnl,
+        unrealized_pnl: calculate_pnl(tokens_worth, cost_basis)?,
+        total_deposited: portfolio_info.total_deposited,
+        total_withdrawn: portfolio_info.total_withdrawn,
+    })
+}
+
+/// Returns the portfolio's balances and net asset value at its last refreshed
+/// prices, for programs composing with it through CPI.
+pub fn get_portfolio_balances(ctx: Context<ViewPortfolio>) -> Result<PortfolioBalances> {
+    portfolio_balances(
+        &ctx.accounts.portfolio_info,
+        &ctx.accounts.token_a_vault.to_account_info(),
+        &ctx.accounts.token_b_vault.to_account_info(),
+        &ctx.accounts.pc_vault.to_account_info(),
+    )
+}
+
+// Validates the accounts a rebalance trades with belong to the portfolio, and
+// that its markets are the ones approved in the global config.
+fn is_valid_rebalance(ctx: &Context<Rebalance>) -> Result<()> {
+    let portfolio_info = &ctx.accounts.portfolio_info;
+    is_valid_markets(
+        portfolio_info,
+        &ctx.accounts.token_a_market,
+        &ctx.accounts.token_b_market,
+    )?;
+    ctx.accounts.config.validate_market(
+        portfolio_info.token_a_mint,
+        portfolio_info.pc_mint,
+        ctx.accounts.token_a_market.market.key(),
+    )?;
+    ctx.accounts.config.validate_market(
+        portfolio_info.token_b_mint,
+        portfolio_info.pc_mint,
+        ctx.accounts.token_b_market.market.key(),
+    )
+}
+
+// Loads the current price from a Pyth price account, rejecting feeds that
+// can't be read, aren't trading or report a non-positive price.
+pub fn load_current_price(price_account: &AccountInfo) -> Result<Price> {
+    let price_feed: PriceFeed = load_price_feed_from_account_info(price_account)
+        .map_err(|_| error!(ErrorCode::OracleUnavailable))?;
+    let price = price_feed
+        .get_current_price()
+        .ok_or(ErrorCode::OracleUnavailable)?;
+    require!(price.price > 0, ErrorCode::OracleUnavailable);
+    Ok(price)
+}
+
 #[derive(Accounts)]
 pub struct RefreshPriceContext<'info> {
     #[account(
             mut,
-            seeds = [PORTFOLIO_INFO_STR.as_bytes(), user.key().as_ref()],
+            seeds = [PORTFOLIO_INFO_STR.as_bytes(), portfolio_info.creator.as_ref()],
             bump,
+            constraint = !portfolio_info.paused @ ErrorCode::PortfolioPaused,
         )]
     portfolio_info: Box<Account<'info, PortfolioInfo>>,
-    /// CHECK:`
+    #[account(
+        seeds = [GLOBAL_CONFIG_STR.as_bytes()],
+        bump = config.bump,
+        constraint = !config.pause_flags.refresh @ ErrorCode::ProgramPaused,
+    )]
+    config: Box<Account<'info, GlobalConfig>>,
+    /// CHECK: Must be the portfolio's token a price feed.
+    #[account(address = portfolio_info.token_a_price_feed @ ErrorCode::PriceFeedMismatch)]
     token_a_pyth_price: AccountInfo<'info>,
-    /// CHECK:`
+    /// CHECK: Must be the portfolio's token b price feed.
+    #[account(address = portfolio_info.token_b_price_feed @ ErrorCode::PriceFeedMismatch)]
     token_b_pyth_price: AccountInfo<'info>,
-    #[account(mut)]
+    #[account(
+        mut,
+        constraint = portfolio_info.can_rebalance(user.key()) @ ErrorCode::UnauthorizedKeeper
+    )]
     user: Signer<'info>,
 }
 
+#[derive(Accounts)]
+pub struct ViewPortfolio<'info> {
+    #[account(
+        seeds = [PORTFOLIO_INFO_STR.as_bytes(), portfolio_info.creator.as_ref()],
+        bump,
+    )]
+    pub portfolio_info: Box<Account<'info, PortfolioInfo>>,
+    #[account(address = portfolio_info.token_a_vault @ ErrorCode::VaultMismatch)]
+    pub token_a_vault: Box<Account<'info, TokenAccount>>,
+    #[account(address = portfolio_info.token_b_vault @ ErrorCode::VaultMismatch)]
+    pub token_b_vault: Box<Account<'info, TokenAccount>>,
+    #[account(address = portfolio_info.pc_vault @ ErrorCode::VaultMismatch)]
+    pub pc_vault: Box<Account<'info, TokenAccount>>,
+}
+
 #[derive(Accounts)]
 pub struct Rebalance<'info> {
     pub token_a_market: MarketAccounts<'info>,
     pub token_b_market: MarketAccounts<'info>,
 
-    #[account(mut)]
+    #[account(
+        mut,
+        constraint = pc_wallet.key() == portfolio_info.pc_vault @ ErrorCode::VaultMismatch
+    )]
     pub pc_wallet: Box<Account<'info, TokenAccount>>,
     /// CHECK: This is the vault signer Acct
     #[account(
             // mut,
             seeds = [VAULT_SIGNER_STR.as_bytes(), portfolio_info.key().as_ref()],
-            bump,
+            bump = portfolio_info.vault_signer_bump,
         )]
     pub vault_signer: AccountInfo<'info>,
-    #[account(mut)]
+    #[account(
+        mut,
+        seeds = [PORTFOLIO_INFO_STR.as_bytes(), portfolio_info.creator.as_ref()],
+        bump,
+        constraint = !portfolio_info.paused @ ErrorCode::PortfolioPaused,
+    )]
     pub portfolio_info: Box<Account<'info, PortfolioInfo>>,
-    /// CHECK:
+    #[account(
+        seeds = [GLOBAL_CONFIG_STR.as_bytes()],
+        bump = config.bump,
+        constraint = !config.pause_flags.rebalance @ ErrorCode::ProgramPaused,
+    )]
+    pub config: Box<Account<'info, GlobalConfig>>,
+    #[account(
+        mut,
+        seeds = [REBALANCE_HISTORY_STR.as_bytes(), portfolio_info.key().as_ref()],
+        bump = rebalance_history.bump,
+    )]
+    pub rebalance_history: Box<Account<'info, RebalanceHistory>>,
+    /// CHECK: Only the Serum dex is accepted.
+    #[account(address = dex::ID)]
     pub dex_program: AccountInfo<'info>,
+    #[account(
+        constraint = portfolio_info.can_rebalance(keeper.key()) @ ErrorCode::UnauthorizedKeeper
+    )]
+    pub keeper: Signer<'info>,
+    // Quote currency account the keeper fee is paid into.
+    #[account(
+        mut,
+        constraint = keeper_fee_wallet.mint == portfolio_info.pc_mint @ ErrorCode::KeeperFeeWalletMismatch
+    )]
+    pub keeper_fee_wallet: Box<Account<'info, TokenAccount>>,
     pub token_program: Program<'info, Token>,
     pub rent: Sysvar<'info, Rent>,
 }
@@ -332,10 +945,69 @@ pub struct MarketAccounts<'info> {
     pub coin_wallet: AccountInfo<'info>,
 }
 
+// Worths and the keeper fee are in native units of the quote currency. The
+// worths are after the keeper fee is paid.
 #[event]
 pub struct AssetsBalanced {
     new_token_a_worth: u64,
     token_a_percentage: u16,
     new_token_b_worth: u64,
     token_b_percentage: u16,
+    new_pc_worth: u64,
+    pc_percentage: u16,
+    keeper: Pubkey,
+    keeper_fee: u64,
+}
+
+// Returned by instructions that change a portfolio's holdings. The net asset
+// value is in native units of the quote currency.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PortfolioBalances {
+    pub token_a_amount: u64,
+    pub token_b_amount: u64,
+    pub pc_amount: u64,
+    pub net_asset_value: u64,
+}
+
+// Returned by `get_portfolio_stats`, in native units of the quote currency.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PortfolioStats {
+    pub net_asset_value: u64,
+    // What the initial deposit would be worth had it been held instead.
+    pub benchmark_nav: u64,
+    // Net asset value less the benchmark's, after fees. Negative when
+    // rebalancing has been a drag.
+    pub rebalancing_premium: i64,
+    pub token_a_cost_basis: u64,
+    pub token_b_cost_basis: u64,
+    pub realized_pnl: i64,
+    // Worth of the tokens held less their cost basis.
+    pub unrealized_pnl: i64,
+    pub total_deposited: u64,
+    pub total_withdrawn: u64,
+}
+
+// Why a rebalance, or one of its orders, didn't go ahead.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SkipReason {
+    // The vault holds nothing, or only dust, to rebalance.
+    EmptyVault,
+    // The order is smaller than one lot of the market's base currency.
+    BelowLotSize,
+    // The order is worth less than the portfolio's dust threshold.
+    BelowDustThreshold,
+    // Every weight is within the portfolio's rebalance band of its target.
+    WithinBand,
+}
+
+#[event]
+pub struct RebalanceSkipped {
+    portfolio_info: Pubkey,
+    // Mint the skipped order would have sold or bought, the default pubkey
+    // when the whole rebalance is skipped.
+    mint: Pubkey,
+    // Native size of the skipped order: of the token for a sell, of the quote
+    // currency for a buy.
+    amount: u64,
+    reason: SkipReason,
 }
@@ -1,11 +1,18 @@
-use crate::constants::{OPEN_ORDERS_A_STR, OPEN_ORDERS_B_STR};
+use crate::constants::{GLOBAL_CONFIG_STR, OPEN_ORDERS_A_STR, OPEN_ORDERS_B_STR};
 
 use anchor_lang::prelude::*;
 
 use anchor_spl::dex::serum_dex::state::OpenOrders;
 use anchor_spl::dex::{self, InitOpenOrders};
+use anchor_spl::token::{Token, TokenAccount};
 
-use crate::{constants::VAULT_SIGNER_STR, state::PortfolioInfo};
+use crate::instructions::rebalance::*;
+use crate::{
+    constants::{PORTFOLIO_INFO_STR, VAULT_SIGNER_STR},
+    errors::ErrorCode,
+    state::{GlobalConfig, PortfolioInfo},
+    utils::{market_mints, OrderbookClient},
+};
 
 // Associated token account for Pubkey::default.
 mod empty {
@@ -14,35 +21,49 @@ mod empty {
 }
 
 /// Convenience API to initialize an open orders account on the Serum DEX.
-pub fn init_accounts<'info>(
-    ctx: Context<'_, '_, '_, 'info, InitAccount<'info>>,
-    bump: InitOrdersBumpSeeds,
-) -> Result<()> {
+pub fn init_accounts<'info>(ctx: Context<'_, '_, '_, 'info, InitAccount<'info>>) -> Result<()> {
     let portfolio_info_key = ctx.accounts.portfolio_info.key();
 
     //Get PDA signer seed of vault owner
     let pda_seeds = &[
         VAULT_SIGNER_STR.as_bytes(),
         portfolio_info_key.as_ref(),
-        &[bump.vault_authority],
+        &[ctx.accounts.portfolio_info.vault_signer_bump],
     ];
 
+    let portfolio_info = &ctx.accounts.portfolio_info;
+    ctx.accounts.config.validate_market(
+        portfolio_info.token_a_mint,
+        portfolio_info.pc_mint,
+        ctx.accounts.market_a.key(),
+    )?;
+    ctx.accounts.config.validate_market(
+        portfolio_info.token_b_mint,
+        portfolio_info.pc_mint,
+        ctx.accounts.market_b.key(),
+    )?;
+
     let market_a_ctx = ctx.accounts.init_open_orders_a_context();
     let market_b_ctx = ctx.accounts.init_open_orders_b_context();
     dex::init_open_orders(market_a_ctx.with_signer(&[pda_seeds.as_ref()]))?;
     dex::init_open_orders(market_b_ctx.with_signer(&[pda_seeds.as_ref()]))?;
 
+    let portfolio_info = &mut ctx.accounts.portfolio_info;
+    portfolio_info.token_a_open_orders = ctx.accounts.open_orders_a.key();
+    portfolio_info.token_b_open_orders = ctx.accounts.open_orders_b.key();
+    portfolio_info.open_orders_a_bump = ctx.bumps["open_orders_a"];
+    portfolio_info.open_orders_b_bump = ctx.bumps["open_orders_b"];
     Ok(())
 }
 
 /// Convenience API to close an open orders account on the Serum DEX.
-pub fn close_account<'info>(ctx: Context<CloseAccount>, vault_signer_bump: u8) -> Result<()> {
+pub fn close_account<'info>(ctx: Context<CloseAccount>) -> Result<()> {
     let portfolio_info_key = ctx.accounts.portfolio_info.key();
     //Get PDA signer seed of vault owner
     let pda_seeds = &[
         VAULT_SIGNER_STR.as_bytes(),
         portfolio_info_key.as_ref(),
-        &[vault_signer_bump],
+        &[ctx.accounts.portfolio_info.vault_signer_bump],
     ];
     let market_a_ctx = CpiContext::new(
         ctx.accounts.dex_program.clone(),
@@ -55,24 +76,46 @@ pub fn close_account<'info>(ctx: Context<CloseAccount>, vault_signer_bump: u8) -
         ctx.accounts.close_open_orders_b_context(),
     );
     dex::close_open_orders(market_b_ctx.with_signer(&[pda_seeds.as_ref()]))?;
+
+    // The portfolio no longer has open orders accounts to sweep or close.
+    let portfolio_info = &mut ctx.accounts.portfolio_info;
+    portfolio_info.token_a_open_orders = Pubkey::default();
+    portfolio_info.token_b_open_orders = Pubkey::default();
     Ok(())
 }
-#[derive(AnchorSerialize, AnchorDeserialize)]
-pub struct InitOrdersBumpSeeds {
-    pub vault_authority: u8,
-    pub open_orders_a: u8,
-    pub open_orders_b: u8,
+/// Cancels every order left on the books by the portfolio's open orders accounts.
+#[access_control(is_valid_crank(&ctx))]
+pub fn cancel_all_orders<'info>(ctx: Context<'_, '_, '_, 'info, CrankOrders<'info>>) -> Result<()> {
+    ctx.accounts
+        .orderbook(ctx.accounts.token_a_market.clone())
+        .cancel_all()?;
+    ctx.accounts
+        .orderbook(ctx.accounts.token_b_market.clone())
+        .cancel_all()?;
+    Ok(())
+}
+
+/// Settles the free balances held by the portfolio's open orders accounts
+/// back into the portfolio vaults.
+#[access_control(is_valid_crank(&ctx))]
+pub fn settle_funds<'info>(ctx: Context<'_, '_, '_, 'info, CrankOrders<'info>>) -> Result<()> {
+    ctx.accounts
+        .orderbook(ctx.accounts.token_a_market.clone())
+        .settle(None)?;
+    ctx.accounts
+        .orderbook(ctx.accounts.token_b_market.clone())
+        .settle(None)?;
+    Ok(())
 }
 
 #[derive(Accounts)]
-#[instruction(bump: InitOrdersBumpSeeds)]
 pub struct InitAccount<'info> {
     /// CHECK
     #[account(
         init,
         seeds = [
             OPEN_ORDERS_A_STR.as_ref(),
-            authority.key().as_ref()
+            portfolio_info.key().as_ref()
         ],
         bump,
         payer = user,
@@ -86,7 +129,7 @@ pub struct InitAccount<'info> {
         init,
         seeds = [
             OPEN_ORDERS_B_STR.as_ref(),
-            authority.key().as_ref()
+            portfolio_info.key().as_ref()
         ],
         bump,
         payer = user,
@@ -95,17 +138,32 @@ pub struct InitAccount<'info> {
         // rent_exempt = skip,
     )]
     open_orders_b: AccountInfo<'info>,
-    /// CHECK
+    /// CHECK: This is the vault signer Acct
+    #[account(
+        seeds = [VAULT_SIGNER_STR.as_bytes(), portfolio_info.key().as_ref()],
+        bump = portfolio_info.vault_signer_bump,
+    )]
     authority: AccountInfo<'info>,
-    /// CHECK
+    /// CHECK: Must be the token a market approved in the global config.
     market_a: AccountInfo<'info>,
-    /// CHECK
+    /// CHECK: Must be the token b market approved in the global config.
     market_b: AccountInfo<'info>,
-    /// CHECK
+    /// CHECK: Only the Serum dex is accepted.
+    #[account(address = dex::ID)]
     dex_program: AccountInfo<'info>,
+    #[account(
+        seeds = [GLOBAL_CONFIG_STR.as_bytes()],
+        bump = config.bump,
+    )]
+    config: Box<Account<'info, GlobalConfig>>,
     #[account(mut)]
     user: Signer<'info>,
-    #[account(mut)]
+    #[account(
+        mut,
+        seeds = [PORTFOLIO_INFO_STR.as_bytes(), portfolio_info.creator.as_ref()],
+        bump,
+        constraint = user.key() == portfolio_info.owner
+    )]
     portfolio_info: Box<Account<'info, PortfolioInfo>>,
     pub rent: Sysvar<'info, Rent>,
     system_program: Program<'info, System>,
@@ -145,9 +203,9 @@ pub struct CloseAccount<'info> {
         mut,
         seeds = [
             OPEN_ORDERS_A_STR.as_ref(),
-            authority.key().as_ref()
+            portfolio_info.key().as_ref()
         ],
-        bump,
+        bump = portfolio_info.open_orders_a_bump,
     )]
     open_orders_a: AccountInfo<'info>,
     /// CHECK
@@ -155,20 +213,30 @@ pub struct CloseAccount<'info> {
         mut,
         seeds = [
             OPEN_ORDERS_B_STR.as_ref(),
-            authority.key().as_ref()
+            portfolio_info.key().as_ref()
         ],
-        bump,
+        bump = portfolio_info.open_orders_b_bump,
     )]
     open_orders_b: AccountInfo<'info>,
-    /// CHECK
+    /// CHECK: This is the vault signer Acct
+    #[account(
+        seeds = [VAULT_SIGNER_STR.as_bytes(), portfolio_info.key().as_ref()],
+        bump = portfolio_info.vault_signer_bump,
+    )]
     authority: AccountInfo<'info>,
-    /// CHECK
+    /// CHECK: The dex checks the open orders account belongs to it.
     market_a: AccountInfo<'info>,
-    /// CHECK
+    /// CHECK: The dex checks the open orders account belongs to it.
     market_b: AccountInfo<'info>,
-    /// CHECK
+    /// CHECK: Only the Serum dex is accepted.
+    #[account(address = dex::ID)]
     dex_program: AccountInfo<'info>,
-    #[account(mut)]
+    #[account(
+        mut,
+        seeds = [PORTFOLIO_INFO_STR.as_bytes(), portfolio_info.creator.as_ref()],
+        bump,
+        constraint = user.key() == portfolio_info.owner
+    )]
     portfolio_info: Box<Account<'info, PortfolioInfo>>,
     #[account(mut)]
     user: Signer<'info>,
@@ -195,3 +263,130 @@ impl<'info> CloseAccount<'info> {
         }
     }
 }
+
+#[derive(Accounts)]
+pub struct CrankOrders<'info> {
+    pub token_a_market: MarketAccounts<'info>,
+    pub token_b_market: MarketAccounts<'info>,
+    #[account(
+        mut,
+        constraint = pc_wallet.key() == portfolio_info.pc_vault @ ErrorCode::VaultMismatch
+    )]
+    pub pc_wallet: Box<Account<'info, TokenAccount>>,
+    /// CHECK: This is the vault signer Acct
+    #[account(
+        seeds = [VAULT_SIGNER_STR.as_bytes(), portfolio_info.key().as_ref()],
+        bump = portfolio_info.vault_signer_bump,
+    )]
+    pub vault_signer: AccountInfo<'info>,
+    #[account(
+        seeds = [PORTFOLIO_INFO_STR.as_bytes(), portfolio_info.creator.as_ref()],
+        bump,
+    )]
+    pub portfolio_info: Box<Account<'info, PortfolioInfo>>,
+    /// CHECK: Only the Serum dex is accepted.
+    #[account(address = dex::ID)]
+    pub dex_program: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+impl<'info> CrankOrders<'info> {
+    fn orderbook(&self, market: MarketAccounts<'info>) -> OrderbookClient<'info> {
+        OrderbookClient {
+            market,
+            authority: self.vault_signer.clone(),
+            pc_wallet: self.pc_wallet.to_account_info().clone(),
+            dex_program: self.dex_program.clone(),
+            token_program: self.token_program.to_account_info().clone(),
+            rent: self.rent.to_account_info().clone(),
+            portfolio_info: self.portfolio_info.clone(),
+        }
+    }
+}
+
+// Markets whose open orders are swept during a withdrawal, passed in as
+// remaining accounts.
+#[derive(Accounts)]
+pub struct OpenOrdersMarkets<'info> {
+    pub token_a_market: MarketAccounts<'info>,
+    pub token_b_market: MarketAccounts<'info>,
+    /// CHECK: Only the Serum dex is accepted.
+    #[account(address = dex::ID)]
+    pub dex_program: AccountInfo<'info>,
+}
+
+fn is_valid_crank(ctx: &Context<CrankOrders>) -> Result<()> {
+    is_valid_markets(
+        &ctx.accounts.portfolio_info,
+        &ctx.accounts.token_a_market,
+        &ctx.accounts.token_b_market,
+    )
+}
+
+// Validates the markets trade the portfolio's tokens against its quote
+// currency, and that settled funds can only flow back into its own vaults.
+pub fn is_valid_markets(
+    portfolio_info: &PortfolioInfo,
+    token_a_market: &MarketAccounts,
+    token_b_market: &MarketAccounts,
+) -> Result<()> {
+    is_valid_market_wallets(portfolio_info, token_a_market, token_b_market)?;
+    is_valid_open_orders(portfolio_info, token_a_market, token_b_market)?;
+    is_valid_market(
+        token_a_market,
+        portfolio_info.token_a_mint,
+        portfolio_info.pc_mint,
+    )?;
+    is_valid_market(
+        token_b_market,
+        portfolio_info.token_b_mint,
+        portfolio_info.pc_mint,
+    )
+}
+
+// Validates settled funds can only flow back into the portfolio's own vaults.
+pub fn is_valid_market_wallets(
+    portfolio_info: &PortfolioInfo,
+    token_a_market: &MarketAccounts,
+    token_b_market: &MarketAccounts,
+) -> Result<()> {
+    require_keys_eq!(
+        token_a_market.coin_wallet.key(),
+        portfolio_info.token_a_vault,
+        ErrorCode::VaultMismatch
+    );
+    require_keys_eq!(
+        token_b_market.coin_wallet.key(),
+        portfolio_info.token_b_vault,
+        ErrorCode::VaultMismatch
+    );
+    Ok(())
+}
+
+// Validates orders go through the portfolio's own open orders accounts.
+fn is_valid_open_orders(
+    portfolio_info: &PortfolioInfo,
+    token_a_market: &MarketAccounts,
+    token_b_market: &MarketAccounts,
+) -> Result<()> {
+    require_keys_eq!(
+        token_a_market.open_orders.key(),
+        portfolio_info.token_a_open_orders,
+        ErrorCode::OpenOrdersMismatch
+    );
+    require_keys_eq!(
+        token_b_market.open_orders.key(),
+        portfolio_info.token_b_open_orders,
+        ErrorCode::OpenOrdersMismatch
+    );
+    Ok(())
+}
+
+// Validates `market` trades `coin_mint` against `pc_mint`.
+fn is_valid_market(market: &MarketAccounts, coin_mint: Pubkey, pc_mint: Pubkey) -> Result<()> {
+    let (market_coin_mint, market_pc_mint) = market_mints(&market.market)?;
+    require_keys_eq!(market_coin_mint, coin_mint, ErrorCode::MarketMintMismatch);
+    require_keys_eq!(market_pc_mint, pc_mint, ErrorCode::MarketMintMismatch);
+    Ok(())
+}
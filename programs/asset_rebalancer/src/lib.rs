@@ -1,11 +1,29 @@
+//! Rebalances a portfolio of two tokens and a quote currency to target
+//! weights by trading on Serum.
+//!
+//! Other programs can create and manage portfolios through CPI by depending on
+//! this crate with the `cpi` feature and calling into `cpi::*`:
+//! - Portfolios are owned by any pubkey, including PDAs that sign through CPI,
+//!   and their accounts are paid for by a separate payer.
+//! - A portfolio's address is derived from its creator alone, so each creator
+//!   has at most one portfolio. Transferring ownership doesn't free the
+//!   creator's address; closing the portfolio does. Callers needing several
+//!   portfolios create each one from a distinct PDA.
+//! - The `pda` module derives the portfolio, vault signer, open orders,
+//!   rebalance history and global config addresses a call needs.
+//! - `deposit`, `rebalance_assets` and `get_portfolio_balances` return the
+//!   portfolio's resulting `PortfolioBalances` as return data, and
+//!   `get_portfolio_stats` its performance against buy-and-hold.
 use anchor_lang::prelude::*;
 pub mod constants;
 pub mod errors;
 pub mod instructions;
+pub mod pda;
 pub mod state;
 pub mod utils;
 
 use instructions::*;
+use state::{PauseFlags, RebalancePolicy, SupportedAsset};
 
 // use state::*;
 
@@ -19,19 +37,147 @@ pub mod asset_rebalancer {
         ctx: Context<Deposit>,
         token_a_percentage: u16,
         token_b_percentage: u16,
-        vault_signer_bump: u8,
-    ) -> Result<()> {
+        pc_percentage: u16,
+    ) -> Result<PortfolioBalances> {
         instructions::deposit_withdraw::deposit(
             ctx,
             token_a_percentage,
             token_b_percentage,
-            vault_signer_bump,
+            pc_percentage,
+        )
+    }
+
+    pub fn create_portfolio<'info>(
+        ctx: Context<'_, '_, '_, 'info, CreatePortfolio<'info>>,
+        token_a_percentage: u16,
+        token_b_percentage: u16,
+        pc_percentage: u16,
+    ) -> Result<PortfolioBalances> {
+        instructions::onboarding::create_portfolio(
+            ctx,
+            token_a_percentage,
+            token_b_percentage,
+            pc_percentage,
         )
     }
 
-    pub fn withdraw(ctx: Context<Withdraw>) -> Result<()> {
+    pub fn close_portfolio<'info>(
+        ctx: Context<'_, '_, '_, 'info, ClosePortfolio<'info>>,
+    ) -> Result<()> {
+        instructions::onboarding::close_portfolio(ctx)
+    }
+
+    pub fn withdraw<'info>(ctx: Context<'_, '_, '_, 'info, Withdraw<'info>>) -> Result<()> {
         instructions::deposit_withdraw::withdraw(ctx)
     }
+    pub fn update_allocation(
+        ctx: Context<OwnerUpdate>,
+        token_a_percentage: u16,
+        token_b_percentage: u16,
+        pc_percentage: u16,
+        delay: i64,
+    ) -> Result<()> {
+        instructions::allocation::update_allocation(
+            ctx,
+            token_a_percentage,
+            token_b_percentage,
+            pc_percentage,
+            delay,
+        )
+    }
+
+    pub fn set_glide_path(
+        ctx: Context<OwnerUpdate>,
+        start_allocation: Allocation,
+        end_allocation: Allocation,
+        start_unix: i64,
+        end_unix: i64,
+    ) -> Result<()> {
+        instructions::allocation::set_glide_path(
+            ctx,
+            start_allocation,
+            end_allocation,
+            start_unix,
+            end_unix,
+        )
+    }
+
+    pub fn set_dust_threshold(ctx: Context<OwnerUpdate>, dust_threshold: u64) -> Result<()> {
+        instructions::allocation::set_dust_threshold(ctx, dust_threshold)
+    }
+
+    pub fn set_rebalance_policy(
+        ctx: Context<OwnerUpdate>,
+        rebalance_policy: RebalancePolicy,
+    ) -> Result<()> {
+        instructions::keeper::set_rebalance_policy(ctx, rebalance_policy)
+    }
+
+    pub fn set_rebalance_delegate(ctx: Context<OwnerUpdate>, delegate: Pubkey) -> Result<()> {
+        instructions::keeper::set_rebalance_delegate(ctx, delegate)
+    }
+
+    pub fn revoke_rebalance_delegate(ctx: Context<OwnerUpdate>) -> Result<()> {
+        instructions::keeper::revoke_rebalance_delegate(ctx)
+    }
+
+    pub fn set_keeper_fee(ctx: Context<OwnerUpdate>, keeper_fee_bps: u16) -> Result<()> {
+        instructions::keeper::set_keeper_fee(ctx, keeper_fee_bps)
+    }
+
+    pub fn set_rebalance_band(ctx: Context<OwnerUpdate>, rebalance_band_bps: u16) -> Result<()> {
+        instructions::keeper::set_rebalance_band(ctx, rebalance_band_bps)
+    }
+
+    pub fn set_fees(
+        ctx: Context<SetFees>,
+        fee_recipient: Pubkey,
+        management_fee_bps: u16,
+        performance_fee_bps: u16,
+    ) -> Result<()> {
+        instructions::fees::set_fees(ctx, fee_recipient, management_fee_bps, performance_fee_bps)
+    }
+
+    pub fn collect_fees(ctx: Context<CollectFees>) -> Result<()> {
+        instructions::fees::collect_fees(ctx)
+    }
+
+    pub fn transfer_ownership(ctx: Context<OwnerUpdate>, new_owner: Pubkey) -> Result<()> {
+        instructions::ownership::transfer_ownership(ctx, new_owner)
+    }
+
+    pub fn accept_ownership(ctx: Context<AcceptOwnership>) -> Result<()> {
+        instructions::ownership::accept_ownership(ctx)
+    }
+
+    pub fn init_config(ctx: Context<InitConfig>) -> Result<()> {
+        instructions::config::init_config(ctx)
+    }
+
+    pub fn set_config_admin(ctx: Context<UpdateConfig>, admin: Pubkey) -> Result<()> {
+        instructions::config::set_config_admin(ctx, admin)
+    }
+
+    pub fn set_pause_flags(ctx: Context<UpdateConfig>, pause_flags: PauseFlags) -> Result<()> {
+        instructions::config::set_pause_flags(ctx, pause_flags)
+    }
+
+    pub fn add_supported_asset(ctx: Context<UpdateConfig>, asset: SupportedAsset) -> Result<()> {
+        instructions::config::add_supported_asset(ctx, asset)
+    }
+
+    pub fn remove_supported_asset(
+        ctx: Context<UpdateConfig>,
+        mint: Pubkey,
+        pc_mint: Pubkey,
+    ) -> Result<()> {
+        instructions::config::remove_supported_asset(ctx, mint, pc_mint)
+    }
+
+    pub fn set_portfolio_paused(ctx: Context<OwnerUpdate>, paused: bool) -> Result<()> {
+        instructions::config::set_portfolio_paused(ctx, paused)
+    }
+
     pub fn refresh_prices(ctx: Context<RefreshPriceContext>) -> Result<()> {
         instructions::rebalance::refresh_prices(ctx)
     }
@@ -39,17 +185,32 @@ pub mod asset_rebalancer {
 
     pub fn rebalance_assets<'info>(
         ctx: Context<'_, '_, '_, 'info, Rebalance<'info>>,
-    ) -> Result<()> {
+    ) -> Result<PortfolioBalances> {
         instructions::rebalance::rebalance_assets(ctx)
     }
 
-    pub fn init_accounts<'info>(
-        ctx: Context<'_, '_, '_, 'info, InitAccount<'info>>,
-        bump: InitOrdersBumpSeeds
+    pub fn get_portfolio_balances(ctx: Context<ViewPortfolio>) -> Result<PortfolioBalances> {
+        instructions::rebalance::get_portfolio_balances(ctx)
+    }
+
+    pub fn get_portfolio_stats(ctx: Context<ViewPortfolio>) -> Result<PortfolioStats> {
+        instructions::rebalance::get_portfolio_stats(ctx)
+    }
+
+    pub fn init_accounts<'info>(ctx: Context<'_, '_, '_, 'info, InitAccount<'info>>) -> Result<()> {
+        instructions::swap::init_accounts(ctx)
+    }
+    pub fn close_account<'info>(ctx: Context<CloseAccount>) -> Result<()> {
+        instructions::swap::close_account(ctx)
+    }
+
+    pub fn cancel_all_orders<'info>(
+        ctx: Context<'_, '_, '_, 'info, CrankOrders<'info>>,
     ) -> Result<()> {
-        instructions::swap::init_accounts(ctx, bump)
+        instructions::swap::cancel_all_orders(ctx)
     }
-    pub fn close_account<'info>(ctx: Context<CloseAccount>, vault_signer_bump: u8) -> Result<()> {
-        instructions::swap::close_account(ctx, vault_signer_bump)
+
+    pub fn settle_funds<'info>(ctx: Context<'_, '_, '_, 'info, CrankOrders<'info>>) -> Result<()> {
+        instructions::swap::settle_funds(ctx)
     }
 }
@@ -0,0 +1,71 @@
+// Derivations of the program's PDAs, for clients and programs calling in
+// through CPI.
+use anchor_lang::prelude::*;
+
+use crate::constants::{
+    GLOBAL_CONFIG_STR, OPEN_ORDERS_A_STR, OPEN_ORDERS_B_STR, PORTFOLIO_INFO_STR,
+    REBALANCE_HISTORY_STR, VAULT_SIGNER_STR,
+};
+
+// The portfolio created by, and first owned by, `creator`. There is one per
+// creator.
+pub fn find_portfolio_info_address(creator: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[PORTFOLIO_INFO_STR.as_bytes(), creator.as_ref()],
+        &crate::ID,
+    )
+}
+
+// The PDA owning a portfolio's vaults and open orders accounts.
+pub fn find_vault_signer_address(portfolio_info: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[VAULT_SIGNER_STR.as_bytes(), portfolio_info.as_ref()],
+        &crate::ID,
+    )
+}
+
+// The portfolio's open orders accounts on its token a and token b markets.
+pub fn find_open_orders_addresses(portfolio_info: &Pubkey) -> ((Pubkey, u8), (Pubkey, u8)) {
+    (
+        Pubkey::find_program_address(
+            &[OPEN_ORDERS_A_STR.as_bytes(), portfolio_info.as_ref()],
+            &crate::ID,
+        ),
+        Pubkey::find_program_address(
+            &[OPEN_ORDERS_B_STR.as_bytes(), portfolio_info.as_ref()],
+            &crate::ID,
+        ),
+    )
+}
+
+// The ring buffer of the portfolio's recent rebalances.
+pub fn find_rebalance_history_address(portfolio_info: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[REBALANCE_HISTORY_STR.as_bytes(), portfolio_info.as_ref()],
+        &crate::ID,
+    )
+}
+
+pub fn find_global_config_address() -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[GLOBAL_CONFIG_STR.as_bytes()], &crate::ID)
+}
+
+#[test]
+pub fn test_find_addresses() {
+    let creator = Pubkey::new_unique();
+    let (portfolio_info, bump) = find_portfolio_info_address(&creator);
+    assert_eq!(
+        Pubkey::create_program_address(
+            &[PORTFOLIO_INFO_STR.as_bytes(), creator.as_ref(), &[bump]],
+            &crate::ID,
+        )
+        .unwrap(),
+        portfolio_info
+    );
+    let (vault_signer, _) = find_vault_signer_address(&portfolio_info);
+    let ((open_orders_a, _), (open_orders_b, _)) = find_open_orders_addresses(&portfolio_info);
+    assert_ne!(open_orders_a, vault_signer);
+    assert_ne!(open_orders_a, open_orders_b);
+    let (rebalance_history, _) = find_rebalance_history_address(&portfolio_info);
+    assert_ne!(rebalance_history, open_orders_a);
+}
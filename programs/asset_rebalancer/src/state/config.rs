@@ -0,0 +1,162 @@
+use anchor_lang::prelude::*;
+
+use crate::errors::ErrorCode;
+
+// Most assets the global config can support.
+pub const MAX_SUPPORTED_ASSETS: usize = 16;
+
+// Program-wide settings, held in a single PDA.
+#[account]
+#[derive(Default)]
+pub struct GlobalConfig {
+    // May pause the program and hand over the config.
+    pub admin: Pubkey,
+    pub pause_flags: PauseFlags,
+    pub bump: u8,
+    // Assets portfolios may hold, each against one quote currency.
+    pub supported_assets: Vec<SupportedAsset>,
+}
+
+impl GlobalConfig {
+    pub const MAX_SIZE: usize = 32 //admin
+    + 4 //pause_flags
+    + 1 //bump
+    + 4 + MAX_SUPPORTED_ASSETS * SupportedAsset::SIZE; //supported_assets
+
+    // The supported asset trading `mint` against `pc_mint`, if any.
+    pub fn supported_asset(&self, mint: Pubkey, pc_mint: Pubkey) -> Option<&SupportedAsset> {
+        self.supported_assets
+            .iter()
+            .find(|asset| asset.mint == mint && asset.pc_mint == pc_mint)
+    }
+
+    // Validates `price_feed` is the canonical feed of `mint` against `pc_mint`.
+    pub fn validate_price_feed(
+        &self,
+        mint: Pubkey,
+        pc_mint: Pubkey,
+        price_feed: Pubkey,
+    ) -> Result<()> {
+        let asset = self
+            .supported_asset(mint, pc_mint)
+            .ok_or(ErrorCode::UnsupportedAsset)?;
+        require_keys_eq!(price_feed, asset.price_feed, ErrorCode::PriceFeedMismatch);
+        Ok(())
+    }
+
+    // Validates `market` is the approved market of `mint` against `pc_mint`.
+    pub fn validate_market(&self, mint: Pubkey, pc_mint: Pubkey, market: Pubkey) -> Result<()> {
+        let asset = self
+            .supported_asset(mint, pc_mint)
+            .ok_or(ErrorCode::UnsupportedAsset)?;
+        require_keys_eq!(market, asset.market, ErrorCode::UnapprovedMarket);
+        Ok(())
+    }
+
+    // Adds `asset`, replacing the entry for the same mint and quote currency.
+    pub fn add_supported_asset(&mut self, asset: SupportedAsset) -> Result<()> {
+        match self
+            .supported_assets
+            .iter_mut()
+            .find(|supported| supported.mint == asset.mint && supported.pc_mint == asset.pc_mint)
+        {
+            Some(supported) => *supported = asset,
+            None => {
+                require!(
+                    self.supported_assets.len() < MAX_SUPPORTED_ASSETS,
+                    ErrorCode::TooManySupportedAssets
+                );
+                self.supported_assets.push(asset);
+            }
+        }
+        Ok(())
+    }
+
+    // Removes the asset trading `mint` against `pc_mint`.
+    pub fn remove_supported_asset(&mut self, mint: Pubkey, pc_mint: Pubkey) -> Result<()> {
+        let index = self
+            .supported_assets
+            .iter()
+            .position(|asset| asset.mint == mint && asset.pc_mint == pc_mint)
+            .ok_or(ErrorCode::UnsupportedAsset)?;
+        self.supported_assets.remove(index);
+        Ok(())
+    }
+}
+
+// An allow-listed asset with its canonical price feed and the Serum market it
+// is traded on against `pc_mint`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct SupportedAsset {
+    pub mint: Pubkey,
+    pub pc_mint: Pubkey,
+    pub price_feed: Pubkey,
+    pub market: Pubkey,
+}
+
+impl SupportedAsset {
+    pub const SIZE: usize = 32 //mint
+    + 32 //pc_mint
+    + 32 //price_feed
+    + 32; //market
+}
+
+// Instructions the admin has halted across all portfolios.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct PauseFlags {
+    pub deposit: bool,
+    pub rebalance: bool,
+    pub refresh: bool,
+    // Only halts sweeping the portfolio's markets on withdrawal or close, the
+    // vaults can always be withdrawn.
+    pub withdraw: bool,
+}
+
+#[test]
+pub fn test_supported_assets() {
+    let pc_mint = Pubkey::new_unique();
+    let asset = SupportedAsset {
+        mint: Pubkey::new_unique(),
+        pc_mint,
+        price_feed: Pubkey::new_unique(),
+        market: Pubkey::new_unique(),
+    };
+    let mut config = GlobalConfig::default();
+    config.add_supported_asset(asset).unwrap();
+    assert_eq!(config.supported_asset(asset.mint, pc_mint), Some(&asset));
+    assert_eq!(
+        config.supported_asset(asset.mint, Pubkey::new_unique()),
+        None
+    );
+    assert!(config
+        .validate_market(asset.mint, pc_mint, asset.market)
+        .is_ok());
+    assert!(config
+        .validate_market(asset.mint, pc_mint, Pubkey::new_unique())
+        .is_err());
+    assert!(config
+        .validate_price_feed(asset.mint, pc_mint, asset.price_feed)
+        .is_ok());
+
+    // Re-adding an asset replaces its feed and market.
+    let moved = SupportedAsset {
+        market: Pubkey::new_unique(),
+        ..asset
+    };
+    config.add_supported_asset(moved).unwrap();
+    assert_eq!(config.supported_assets, vec![moved]);
+
+    config.remove_supported_asset(asset.mint, pc_mint).unwrap();
+    assert!(config.supported_assets.is_empty());
+    assert!(config.remove_supported_asset(asset.mint, pc_mint).is_err());
+
+    for _ in 0..MAX_SUPPORTED_ASSETS {
+        config
+            .add_supported_asset(SupportedAsset {
+                mint: Pubkey::new_unique(),
+                ..asset
+            })
+            .unwrap();
+    }
+    assert!(config.add_supported_asset(asset).is_err());
+}
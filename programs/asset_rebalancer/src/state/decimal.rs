@@ -1,29 +1,246 @@
+use std::cmp::Ordering;
+
 use anchor_lang::prelude::*;
+use pyth_sdk_solana::Price;
 
-#[account]
+// A signed fixed-point number worth `value / 10^decimals`.
+//
+// Arithmetic is checked and returns `None` on overflow, division by zero or
+// when a result can't be represented, mirroring the `checked_*` integer ops.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default)]
 pub struct Decimal {
     pub value: i128,
     pub decimals: u32,
 }
 
+// How to round when a result has more precision than it can be stored at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rounding {
+    // Towards negative infinity.
+    Down,
+    // Towards positive infinity.
+    Up,
+    // To the nearest value, halves rounding up.
+    Nearest,
+}
+
 impl Decimal {
     pub fn new(value: i128, decimals: u32) -> Self {
         Decimal { value, decimals }
     }
+
+    pub fn zero() -> Self {
+        Decimal::new(0, 0)
+    }
+
+    // A native token amount of a mint with `decimals`.
+    pub fn from_token_amount(amount: u64, decimals: u8) -> Self {
+        Decimal::new(amount.into(), decimals.into())
+    }
+
+    // A mantissa and exponent, as used by Pyth, i.e., `mantissa * 10^expo`.
+    pub fn from_mantissa(mantissa: i128, expo: i32) -> Option<Self> {
+        if expo <= 0 {
+            Some(Decimal::new(mantissa, expo.unsigned_abs()))
+        } else {
+            let value = mantissa.checked_mul(10i128.checked_pow(expo.unsigned_abs())?)?;
+            Some(Decimal::new(value, 0))
+        }
+    }
+
+    pub fn from_price(price: &Price) -> Option<Self> {
+        Decimal::from_mantissa(price.price.into(), price.expo)
+    }
+
+    // Converts back into a native token amount of a mint with `decimals`.
+    pub fn to_token_amount(self, decimals: u8, rounding: Rounding) -> Option<u64> {
+        u64::try_from(self.rescale(decimals.into(), rounding)?.value).ok()
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.value == 0
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.value < 0
+    }
+
+    // Returns the same number at `decimals`, rounding if precision is lost.
+    pub fn rescale(self, decimals: u32, rounding: Rounding) -> Option<Self> {
+        if decimals >= self.decimals {
+            let value = self.scaled_to(decimals)?;
+            Some(Decimal::new(value, decimals))
+        } else {
+            let divisor = 10i128.checked_pow(self.decimals - decimals)?;
+            let value = div_rounded(self.value, divisor, rounding)?;
+            Some(Decimal::new(value, decimals))
+        }
+    }
+
+    // Sum at the larger of both decimals.
+    pub fn checked_add(self, rhs: Decimal) -> Option<Self> {
+        let decimals = self.decimals.max(rhs.decimals);
+        let value = self
+            .scaled_to(decimals)?
+            .checked_add(rhs.scaled_to(decimals)?)?;
+        Some(Decimal::new(value, decimals))
+    }
+
+    // Difference at the larger of both decimals.
+    pub fn checked_sub(self, rhs: Decimal) -> Option<Self> {
+        let decimals = self.decimals.max(rhs.decimals);
+        let value = self
+            .scaled_to(decimals)?
+            .checked_sub(rhs.scaled_to(decimals)?)?;
+        Some(Decimal::new(value, decimals))
+    }
+
+    // Exact product, at the sum of both decimals.
+    pub fn checked_mul(self, rhs: Decimal) -> Option<Self> {
+        let value = self.value.checked_mul(rhs.value)?;
+        let decimals = self.decimals.checked_add(rhs.decimals)?;
+        Some(Decimal::new(value, decimals))
+    }
+
+    // Quotient at `self`'s decimals. Rescale `self` first for more precision.
+    pub fn checked_div(self, rhs: Decimal, rounding: Rounding) -> Option<Self> {
+        let numerator = self.value.checked_mul(10i128.checked_pow(rhs.decimals)?)?;
+        let value = div_rounded(numerator, rhs.value, rounding)?;
+        Some(Decimal::new(value, self.decimals))
+    }
+
+    // The raw value at `decimals`, which must not be below `self.decimals`.
+    fn scaled_to(&self, decimals: u32) -> Option<i128> {
+        self.value
+            .checked_mul(10i128.checked_pow(decimals.checked_sub(self.decimals)?)?)
+    }
+}
+
+// Divides `numerator` by `denominator`, rounding the quotient as requested.
+fn div_rounded(numerator: i128, denominator: i128, rounding: Rounding) -> Option<i128> {
+    if denominator == 0 {
+        return None;
+    }
+    let (numerator, denominator) = if denominator < 0 {
+        (numerator.checked_neg()?, denominator.checked_neg()?)
+    } else {
+        (numerator, denominator)
+    };
+    // With a positive denominator euclidean division floors.
+    let quotient = numerator.checked_div_euclid(denominator)?;
+    let remainder = numerator.checked_rem_euclid(denominator)?;
+    let round_up = match rounding {
+        Rounding::Down => false,
+        Rounding::Up => remainder > 0,
+        Rounding::Nearest => remainder >= denominator - remainder,
+    };
+    if round_up {
+        quotient.checked_add(1)
+    } else {
+        Some(quotient)
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Decimal {}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let decimals = self.decimals.max(other.decimals);
+        match (self.scaled_to(decimals), other.scaled_to(decimals)) {
+            (Some(value), Some(other_value)) => value.cmp(&other_value),
+            // Only the side with fewer decimals is scaled up, so it overflowing
+            // means its magnitude is beyond anything the other side can hold.
+            (None, _) if self.is_negative() => Ordering::Less,
+            (None, _) => Ordering::Greater,
+            (_, None) if other.is_negative() => Ordering::Greater,
+            (_, None) => Ordering::Less,
+        }
+    }
 }
 
 impl std::fmt::Display for Decimal {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut scaled_val = self.value.to_string();
+        let mut scaled_val = self.value.unsigned_abs().to_string();
         if scaled_val.len() <= self.decimals as usize {
             scaled_val.insert_str(
                 0,
                 &vec!["0"; self.decimals as usize - scaled_val.len()].join(""),
             );
             scaled_val.insert_str(0, "0.");
-        } else {
+        } else if self.decimals > 0 {
             scaled_val.insert(scaled_val.len() - self.decimals as usize, '.');
         }
+        if self.is_negative() {
+            scaled_val.insert(0, '-');
+        }
         f.write_str(&scaled_val)
     }
 }
+
+#[test]
+pub fn test_decimal_arithmetic() {
+    let a = Decimal::new(150, 2);
+    let b = Decimal::new(25, 1);
+    assert_eq!(a.checked_add(b).unwrap(), Decimal::new(400, 2));
+    assert_eq!(a.checked_sub(b).unwrap().to_string(), "-1.00");
+    assert_eq!(a.checked_mul(b).unwrap().to_string(), "3.750");
+    assert_eq!(
+        a.checked_div(b, Rounding::Down).unwrap(),
+        Decimal::new(60, 2)
+    );
+    assert_eq!(a.checked_div(Decimal::zero(), Rounding::Down), None);
+    assert_eq!(Decimal::new(i128::MAX, 0).checked_add(a), None);
+    assert!(Decimal::new(1, 0) > Decimal::new(99, 2));
+    assert!(Decimal::new(i128::MAX, 0) > Decimal::new(1, 30));
+}
+
+#[test]
+pub fn test_decimal_rounding() {
+    let value = Decimal::new(1_250, 3);
+    assert_eq!(value.rescale(2, Rounding::Down).unwrap().value, 125);
+    assert_eq!(value.rescale(1, Rounding::Down).unwrap().value, 12);
+    assert_eq!(value.rescale(1, Rounding::Up).unwrap().value, 13);
+    assert_eq!(value.rescale(1, Rounding::Nearest).unwrap().value, 13);
+    assert_eq!(
+        Decimal::new(-1_250, 3)
+            .rescale(1, Rounding::Down)
+            .unwrap()
+            .value,
+        -13
+    );
+    assert_eq!(value.rescale(5, Rounding::Down).unwrap().value, 125_000);
+}
+
+#[test]
+pub fn test_decimal_conversions() {
+    assert_eq!(
+        Decimal::from_mantissa(12_345, -2).unwrap().to_string(),
+        "123.45"
+    );
+    assert_eq!(Decimal::from_mantissa(12, 3).unwrap().value, 12_000);
+    assert_eq!(
+        Decimal::from_token_amount(2_500_001, 6)
+            .to_token_amount(2, Rounding::Down)
+            .unwrap(),
+        250
+    );
+    assert_eq!(
+        Decimal::from_token_amount(2_500_001, 6)
+            .to_token_amount(2, Rounding::Up)
+            .unwrap(),
+        251
+    );
+    assert_eq!(Decimal::new(-1, 0).to_token_amount(0, Rounding::Down), None);
+}
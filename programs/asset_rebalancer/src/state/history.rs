@@ -0,0 +1,124 @@
+use anchor_lang::prelude::*;
+
+// Most rebalances a portfolio's history keeps, older ones are overwritten.
+pub const REBALANCE_HISTORY_LEN: usize = 32;
+
+// A portfolio's most recent rebalances, held in a PDA next to it so they can
+// be read without indexing the program's logs.
+#[account]
+#[derive(Default)]
+pub struct RebalanceHistory {
+    pub portfolio_info: Pubkey,
+    pub bump: u8,
+    // Slot the next record is written to. Once the history is full, this is
+    // also the oldest record.
+    pub next_index: u16,
+    // Rebalances recorded since the history was created.
+    pub total_rebalances: u64,
+    pub records: Vec<RebalanceRecord>,
+}
+
+impl RebalanceHistory {
+    pub const MAX_SIZE: usize = 32 //portfolio_info
+    + 1 //bump
+    + 2 //next_index
+    + 8 //total_rebalances
+    + 4 + REBALANCE_HISTORY_LEN * RebalanceRecord::SIZE; //records
+
+    // Appends `record`, overwriting the oldest one once the history is full.
+    pub fn push(&mut self, record: RebalanceRecord) {
+        let index = self.next_index as usize;
+        if self.records.len() < REBALANCE_HISTORY_LEN {
+            self.records.push(record);
+        } else {
+            self.records[index] = record;
+        }
+        self.next_index = ((index + 1) % REBALANCE_HISTORY_LEN) as u16;
+        self.total_rebalances = self.total_rebalances.saturating_add(1);
+    }
+
+    // The most recent rebalance, if any.
+    pub fn latest(&self) -> Option<&RebalanceRecord> {
+        let index = (self.next_index as usize + REBALANCE_HISTORY_LEN - 1) % REBALANCE_HISTORY_LEN;
+        self.records.get(index)
+    }
+}
+
+// What a single rebalance saw and did. Prices are the Pyth mantissa and
+// exponent it used, weights are in basis points of the portfolio's worth and
+// amounts are in native units of each mint.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct RebalanceRecord {
+    pub unix_timestamp: i64,
+    pub token_a_price: i128,
+    pub token_a_price_expo: i32,
+    pub token_b_price: i128,
+    pub token_b_price_expo: i32,
+    pub token_a_weight_before: u16,
+    pub token_b_weight_before: u16,
+    pub pc_weight_before: u16,
+    pub token_a_weight_after: u16,
+    pub token_b_weight_after: u16,
+    pub pc_weight_after: u16,
+    pub token_a_sold: u64,
+    pub token_a_bought: u64,
+    pub token_b_sold: u64,
+    pub token_b_bought: u64,
+    // Quote currency left over from transitive swaps, see `DidSwap`.
+    pub spill_amount: u64,
+    pub keeper_fee: u64,
+    // Net asset value after the rebalance and what the initial deposit would
+    // be worth had it been held instead, in native quote units.
+    pub net_asset_value: u64,
+    pub benchmark_nav: u64,
+}
+
+impl RebalanceRecord {
+    pub const SIZE: usize = 8 //unix_timestamp
+    + 16 //token_a_price
+    + 4 //token_a_price_expo
+    + 16 //token_b_price
+    + 4 //token_b_price_expo
+    + 2 //token_a_weight_before
+    + 2 //token_b_weight_before
+    + 2 //pc_weight_before
+    + 2 //token_a_weight_after
+    + 2 //token_b_weight_after
+    + 2 //pc_weight_after
+    + 8 //token_a_sold
+    + 8 //token_a_bought
+    + 8 //token_b_sold
+    + 8 //token_b_bought
+    + 8 //spill_amount
+    + 8 //keeper_fee
+    + 8 //net_asset_value
+    + 8; //benchmark_nav
+}
+
+#[test]
+pub fn test_rebalance_history() {
+    let mut history = RebalanceHistory::default();
+    assert_eq!(history.latest(), None);
+
+    let record = |unix_timestamp| RebalanceRecord {
+        unix_timestamp,
+        ..RebalanceRecord::default()
+    };
+    for unix_timestamp in 0..REBALANCE_HISTORY_LEN as i64 {
+        history.push(record(unix_timestamp));
+    }
+    assert_eq!(history.records.len(), REBALANCE_HISTORY_LEN);
+    assert_eq!(history.next_index, 0);
+    assert_eq!(history.latest().unwrap().unix_timestamp, 31);
+
+    // The oldest record is overwritten once the history is full.
+    history.push(record(100));
+    assert_eq!(history.records.len(), REBALANCE_HISTORY_LEN);
+    assert_eq!(history.records[0].unix_timestamp, 100);
+    assert_eq!(history.latest().unwrap().unix_timestamp, 100);
+    assert_eq!(history.total_rebalances, 33);
+
+    let mut serialized = Vec::new();
+    history.try_serialize(&mut serialized).unwrap();
+    assert!(serialized.len() <= 8 + RebalanceHistory::MAX_SIZE);
+}
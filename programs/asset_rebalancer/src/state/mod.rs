@@ -1,5 +1,9 @@
+pub mod config;
+pub mod history;
 pub mod portfolio;
 pub mod decimal;
 
+pub use config::*;
+pub use history::*;
 pub use portfolio::*;
 pub use decimal::*;
\ No newline at end of file
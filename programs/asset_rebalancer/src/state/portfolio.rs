@@ -1,9 +1,20 @@
 use anchor_lang::prelude::*;
 
+use crate::{
+    constants::{DEFAULT_REBALANCE_BAND_BPS, MAX_BPS},
+    errors::ErrorCode,
+};
+
+// Fields have been added throughout the account since its first version, so
+// `PortfolioInfo` accounts created by earlier versions of the program can't be
+// read by this one, nor resized in place. There is no migration: portfolios
+// have to be withdrawn before upgrading and created again afterwards.
 #[account]
-#[derive(Copy)]
+#[derive(Copy, Default)]
 pub struct PortfolioInfo {
     pub owner: Pubkey,
+    // The portfolio's first owner, whose key its address is derived from.
+    pub creator: Pubkey,
     pub token_a_mint: Pubkey,
     pub token_a_decimals: u8,
     pub token_b_mint: Pubkey,
@@ -17,15 +28,104 @@ pub struct PortfolioInfo {
     pub token_b_price_feed: Pubkey,
     pub token_a_price: i128,
     pub token_b_price: i128,
+    pub token_a_price_expo: i32,
+    pub token_b_price_expo: i32,
     pub token_a_percentage: u16,
     pub token_b_percentage: u16,
+    pub pc_percentage: u16,
+    pub pending_token_a_percentage: u16,
+    pub pending_token_b_percentage: u16,
+    pub pending_pc_percentage: u16,
+    // When the pending allocation takes effect, 0 if there is none.
+    pub pending_allocation_unix: i64,
+    // Allocation the targets glide from at `glide_start_unix` to the end
+    // allocation at `glide_end_unix`. No glide path is set if the end is 0.
+    pub glide_start_token_a_percentage: u16,
+    pub glide_start_token_b_percentage: u16,
+    pub glide_start_pc_percentage: u16,
+    pub glide_end_token_a_percentage: u16,
+    pub glide_end_token_b_percentage: u16,
+    pub glide_end_pc_percentage: u16,
+    pub glide_start_unix: i64,
+    pub glide_end_unix: i64,
+    // Orders worth less than this, in native quote units, are skipped on
+    // rebalance.
+    pub dust_threshold: u64,
+    // Who may refresh prices and rebalance the portfolio.
+    pub rebalance_policy: RebalancePolicy,
+    // Keeper allowed to rebalance under `RebalancePolicy::Delegate`, the
+    // default pubkey if there is none.
+    pub rebalance_delegate: Pubkey,
+    // Paid to the keeper out of the quote vault for each rebalance that
+    // trades, in basis points of the quote currency traded.
+    pub keeper_fee_bps: u16,
+    // A rebalance only trades once a weight has drifted at least this far
+    // from its target, in basis points.
+    pub rebalance_band_bps: u16,
+    // Management and performance fees are paid to the fee recipient's quote
+    // currency account.
+    pub fee_recipient: Pubkey,
+    // Annual management fee, in basis points of net asset value.
+    pub management_fee_bps: u16,
+    // Fee on net asset value above the high-water mark, in basis points.
+    pub performance_fee_bps: u16,
+    // Highest net asset value fees were collected at, in native quote units.
+    pub high_water_mark: u64,
+    // Fees accrued that the quote vault couldn't cover yet.
+    pub unpaid_fees: u64,
+    pub last_fee_accrual_unix: i64,
+    // Set by the owner to halt refreshing prices and rebalancing.
+    pub paused: bool,
+    // Proposed owner that has yet to accept, the default pubkey if none.
+    pub pending_owner: Pubkey,
     pub vault_signer_bump: u8,
+    // The portfolio's open orders accounts on its token a and token b markets,
+    // and their canonical bumps, set once they're initialized.
+    pub token_a_open_orders: Pubkey,
+    pub token_b_open_orders: Pubkey,
+    pub open_orders_a_bump: u8,
+    pub open_orders_b_bump: u8,
+    // What the vaults' tokens cost, at the prices they were deposited or
+    // bought at, in native quote units. Sales take out the average cost of
+    // the units sold.
+    pub token_a_cost_basis: u64,
+    pub token_b_cost_basis: u64,
+    // Proceeds of the tokens sold on rebalance less their cost basis, in
+    // native quote units.
+    pub realized_pnl: i64,
+    // Worth of everything deposited and withdrawn, in native quote units.
+    pub total_deposited: u64,
+    pub total_withdrawn: u64,
+    // Native amounts first deposited, which the buy-and-hold benchmark keeps.
+    pub token_a_initial_amount: u64,
+    pub token_b_initial_amount: u64,
+    pub pc_initial_amount: u64,
     pub last_update_unix: i64,
 }
 
+// One of the two tokens a new portfolio holds, at its current price.
+pub struct AssetInitParams {
+    pub mint: Pubkey,
+    pub decimals: u8,
+    pub vault: Pubkey,
+    pub price_feed: Pubkey,
+    pub price: i128,
+    pub price_expo: i32,
+    pub percentage: u16,
+}
+
+// The quote currency a new portfolio holds as cash and trades against.
+pub struct QuoteInitParams {
+    pub mint: Pubkey,
+    pub decimals: u8,
+    pub vault: Pubkey,
+    pub percentage: u16,
+}
+
 impl PortfolioInfo {
     pub const MAX_SIZE: usize = 32 //token_a_mint
     + 32 //owner
+    + 32 //creator
     + 1 //token_a_decimals
     + 32 //token_b_mint
     + 1 //token_b_decimals
@@ -38,50 +138,305 @@ impl PortfolioInfo {
     + 32 //token_b_price_feed
     + 16 //token_a_price
     + 16 //token_b_price
+    + 4 //token_a_price_expo
+    + 4 //token_b_price_expo
     + 2 //token_a_percentage
     + 2 //token_b_percentage
+    + 2 //pc_percentage
+    + 2 //pending_token_a_percentage
+    + 2 //pending_token_b_percentage
+    + 2 //pending_pc_percentage
+    + 8 //pending_allocation_unix
+    + 2 //glide_start_token_a_percentage
+    + 2 //glide_start_token_b_percentage
+    + 2 //glide_start_pc_percentage
+    + 2 //glide_end_token_a_percentage
+    + 2 //glide_end_token_b_percentage
+    + 2 //glide_end_pc_percentage
+    + 8 //glide_start_unix
+    + 8 //glide_end_unix
+    + 8 //dust_threshold
+    + 1 //rebalance_policy
+    + 32 //rebalance_delegate
+    + 2 //keeper_fee_bps
+    + 2 //rebalance_band_bps
+    + 32 //fee_recipient
+    + 2 //management_fee_bps
+    + 2 //performance_fee_bps
+    + 8 //high_water_mark
+    + 8 //unpaid_fees
+    + 8 //last_fee_accrual_unix
+    + 1 //paused
+    + 32 //pending_owner
     + 1 //vault_signer_bump
+    + 32 //token_a_open_orders
+    + 32 //token_b_open_orders
+    + 1 //open_orders_a_bump
+    + 1 //open_orders_b_bump
+    + 8 //token_a_cost_basis
+    + 8 //token_b_cost_basis
+    + 8 //realized_pnl
+    + 8 //total_deposited
+    + 8 //total_withdrawn
+    + 8 //token_a_initial_amount
+    + 8 //token_b_initial_amount
+    + 8 //pc_initial_amount
     + 8; //last_update_unix
 
     pub fn init(
         &mut self,
         owner: Pubkey,
-        token_a_mint: Pubkey,
-        token_a_decimals: u8,
-        token_b_mint: Pubkey,
-        token_b_decimals: u8,
-        token_a_vault: Pubkey,
-        token_b_vault: Pubkey,
-        token_a_price_feed: Pubkey,
-        token_b_price_feed: Pubkey,
-        token_a_price: i128,
-        token_b_price: i128,
-        pc_vault: Pubkey,
-        pc_mint: Pubkey,
-        pc_decimals: u8,
-        token_a_percentage: u16,
-        token_b_percentage: u16,
+        token_a: AssetInitParams,
+        token_b: AssetInitParams,
+        pc: QuoteInitParams,
         vault_signer_bump: u8,
     ) -> Result<()> {
         self.owner = owner;
-        self.token_a_mint = token_a_mint;
-        self.token_a_decimals = token_a_decimals;
-        self.token_b_mint = token_b_mint;
-        self.token_b_decimals = token_b_decimals;
-        self.token_a_vault = token_a_vault;
-        self.token_b_vault = token_b_vault;
-        self.token_a_price_feed = token_a_price_feed;
-        self.token_b_price_feed = token_b_price_feed;
-        self.token_a_price = token_a_price;
-        self.token_b_price = token_b_price;
-        self.pc_vault = pc_vault;
-        self.pc_mint = pc_mint;
-        self.pc_decimals = pc_decimals;
-        self.token_a_percentage = token_a_percentage;
-        self.token_b_percentage = token_b_percentage;
+        self.creator = owner;
+        self.token_a_mint = token_a.mint;
+        self.token_a_decimals = token_a.decimals;
+        self.token_a_vault = token_a.vault;
+        self.token_a_price_feed = token_a.price_feed;
+        self.token_a_price = token_a.price;
+        self.token_a_price_expo = token_a.price_expo;
+        self.token_a_percentage = token_a.percentage;
+        self.token_b_mint = token_b.mint;
+        self.token_b_decimals = token_b.decimals;
+        self.token_b_vault = token_b.vault;
+        self.token_b_price_feed = token_b.price_feed;
+        self.token_b_price = token_b.price;
+        self.token_b_price_expo = token_b.price_expo;
+        self.token_b_percentage = token_b.percentage;
+        self.pc_mint = pc.mint;
+        self.pc_decimals = pc.decimals;
+        self.pc_vault = pc.vault;
+        self.pc_percentage = pc.percentage;
         self.vault_signer_bump = vault_signer_bump;
-        self.last_update_unix = Clock::get().unwrap().unix_timestamp;
+        self.rebalance_band_bps = DEFAULT_REBALANCE_BAND_BPS;
+        self.last_update_unix = Clock::get()?.unix_timestamp;
         Ok(())
     }
+    // Ensures both assets are held and all percentages, in basis points, add
+    // up to `MAX_BPS`.
+    pub fn validate_allocation(
+        token_a_percentage: u16,
+        token_b_percentage: u16,
+        pc_percentage: u16,
+    ) -> Result<()> {
+        require!(
+            token_a_percentage > 0
+                && token_b_percentage > 0
+                && u32::from(token_a_percentage)
+                    + u32::from(token_b_percentage)
+                    + u32::from(pc_percentage)
+                    == u32::from(MAX_BPS),
+            ErrorCode::InvalidAllocation
+        );
+        Ok(())
+    }
+
+    // Moves the pending allocation into the targets once it's due. Returns
+    // whether the targets changed.
+    pub fn apply_pending_allocation(&mut self, unix_timestamp: i64) -> bool {
+        if self.pending_allocation_unix == 0 || self.pending_allocation_unix > unix_timestamp {
+            return false;
+        }
+        self.token_a_percentage = self.pending_token_a_percentage;
+        self.token_b_percentage = self.pending_token_b_percentage;
+        self.pc_percentage = self.pending_pc_percentage;
+        self.pending_allocation_unix = 0;
+        // An explicit allocation replaces any glide path.
+        self.glide_end_unix = 0;
+        true
+    }
+
+    // Moves the targets to where the glide path is at `unix_timestamp`, and
+    // clears the glide path once it's complete. Returns whether the targets
+    // changed.
+    pub fn apply_glide_path(&mut self, unix_timestamp: i64) -> Result<bool> {
+        if self.glide_end_unix == 0 {
+            return Ok(false);
+        }
+        let duration = self
+            .glide_end_unix
+            .checked_sub(self.glide_start_unix)
+            .ok_or(ErrorCode::MathOverflow)?;
+        let elapsed = unix_timestamp
+            .checked_sub(self.glide_start_unix)
+            .ok_or(ErrorCode::MathOverflow)?
+            .clamp(0, duration);
+
+        let token_a_percentage = interpolate_percentage(
+            self.glide_start_token_a_percentage,
+            self.glide_end_token_a_percentage,
+            elapsed,
+            duration,
+        )?;
+        let token_b_percentage = interpolate_percentage(
+            self.glide_start_token_b_percentage,
+            self.glide_end_token_b_percentage,
+            elapsed,
+            duration,
+        )?;
+        // Derived from the other two so the allocation still adds up to
+        // `MAX_BPS`.
+        let pc_percentage = MAX_BPS
+            .checked_sub(token_a_percentage)
+            .and_then(|rest| rest.checked_sub(token_b_percentage))
+            .ok_or(ErrorCode::MathOverflow)?;
+        if elapsed == duration {
+            self.glide_end_unix = 0;
+        }
+
+        let changed = token_a_percentage != self.token_a_percentage
+            || token_b_percentage != self.token_b_percentage
+            || pc_percentage != self.pc_percentage;
+        self.token_a_percentage = token_a_percentage;
+        self.token_b_percentage = token_b_percentage;
+        self.pc_percentage = pc_percentage;
+        Ok(changed)
+    }
+
+    // Whether every current weight is within the rebalance band of its
+    // target, in which case a rebalance doesn't trade.
+    pub fn within_rebalance_band(
+        &self,
+        token_a_percentage: u16,
+        token_b_percentage: u16,
+        pc_percentage: u16,
+    ) -> bool {
+        [
+            (token_a_percentage, self.token_a_percentage),
+            (token_b_percentage, self.token_b_percentage),
+            (pc_percentage, self.pc_percentage),
+        ]
+        .iter()
+        .all(|(current, target)| current.abs_diff(*target) < self.rebalance_band_bps)
+    }
+
+    // Whether the portfolio's open orders accounts were created, by
+    // `init_accounts` or `create_portfolio`.
+    pub fn has_open_orders(&self) -> bool {
+        self.token_a_open_orders != Pubkey::default()
+    }
+
+    // Whether the portfolio charges fees or still owes some.
+    pub fn charges_fees(&self) -> bool {
+        self.management_fee_bps > 0 || self.performance_fee_bps > 0 || self.unpaid_fees > 0
+    }
+
+    // Whether `keeper` may rebalance the portfolio under its rebalance policy.
+    pub fn can_rebalance(&self, keeper: Pubkey) -> bool {
+        match self.rebalance_policy {
+            RebalancePolicy::OwnerOnly => keeper == self.owner,
+            RebalancePolicy::Delegate => keeper == self.owner || keeper == self.rebalance_delegate,
+            RebalancePolicy::Permissionless => true,
+        }
+    }
+}
+
+// Who, besides the owner, may rebalance a portfolio.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum RebalancePolicy {
+    #[default]
+    OwnerOnly,
+    // The owner or the portfolio's `rebalance_delegate`.
+    Delegate,
+    Permissionless,
+}
+
+// Linearly interpolates between two percentages, `elapsed` out of `duration`
+// of the way from `start` to `end`.
+fn interpolate_percentage(start: u16, end: u16, elapsed: i64, duration: i64) -> Result<u16> {
+    let start = i128::from(start);
+    let end = i128::from(end);
+    let moved = (end - start)
+        .checked_mul(i128::from(elapsed))
+        .and_then(|moved| moved.checked_div(i128::from(duration)))
+        .ok_or(ErrorCode::MathOverflow)?;
+    u16::try_from(start + moved).map_err(|_| error!(ErrorCode::MathOverflow))
+}
+
+#[test]
+pub fn test_validate_allocation() {
+    assert!(PortfolioInfo::validate_allocation(4500, 4500, 1000).is_ok());
+    assert!(PortfolioInfo::validate_allocation(3000, 7000, 0).is_ok());
+    assert!(PortfolioInfo::validate_allocation(0, 10_000, 0).is_err());
+    assert!(PortfolioInfo::validate_allocation(300, 700, 0).is_err());
+    assert!(PortfolioInfo::validate_allocation(u16::MAX, 10_000, 0).is_err());
+}
+
+#[test]
+pub fn test_interpolate_percentage() {
+    assert_eq!(interpolate_percentage(8000, 4000, 0, 12).unwrap(), 8000);
+    assert_eq!(interpolate_percentage(8000, 4000, 6, 12).unwrap(), 6000);
+    assert_eq!(interpolate_percentage(2000, 6000, 3, 12).unwrap(), 3000);
+    assert_eq!(interpolate_percentage(8000, 4000, 11, 12).unwrap(), 4334);
+    assert_eq!(interpolate_percentage(8000, 4000, 12, 12).unwrap(), 4000);
+    assert!(interpolate_percentage(8000, 4000, 1, 0).is_err());
+}
+
+#[test]
+pub fn test_apply_glide_path_extreme_timestamps() {
+    let mut portfolio_info = PortfolioInfo {
+        glide_start_token_a_percentage: 8000,
+        glide_start_token_b_percentage: 2000,
+        glide_end_token_a_percentage: 2000,
+        glide_end_token_b_percentage: 8000,
+        glide_start_unix: i64::MIN,
+        glide_end_unix: i64::MAX,
+        ..Default::default()
+    };
+    assert!(portfolio_info.apply_glide_path(0).is_err());
+
+    portfolio_info.glide_start_unix = 1;
+    assert!(portfolio_info.apply_glide_path(i64::MIN).is_err());
+    assert!(portfolio_info.apply_glide_path(i64::MAX).unwrap());
+    assert_eq!(portfolio_info.token_a_percentage, 2000);
+    assert_eq!(portfolio_info.token_b_percentage, 8000);
+    assert_eq!(portfolio_info.pc_percentage, 0);
+    assert_eq!(portfolio_info.glide_end_unix, 0);
 }
 
+#[test]
+pub fn test_can_rebalance() {
+    let owner = Pubkey::new_unique();
+    let delegate = Pubkey::new_unique();
+    let stranger = Pubkey::new_unique();
+    let mut portfolio_info = PortfolioInfo {
+        owner,
+        rebalance_policy: RebalancePolicy::OwnerOnly,
+        rebalance_delegate: delegate,
+        ..Default::default()
+    };
+    assert!(portfolio_info.can_rebalance(owner));
+    assert!(!portfolio_info.can_rebalance(delegate));
+
+    portfolio_info.rebalance_policy = RebalancePolicy::Delegate;
+    assert!(portfolio_info.can_rebalance(owner));
+    assert!(portfolio_info.can_rebalance(delegate));
+    assert!(!portfolio_info.can_rebalance(stranger));
+
+    portfolio_info.rebalance_policy = RebalancePolicy::Permissionless;
+    assert!(portfolio_info.can_rebalance(stranger));
+}
+
+#[test]
+pub fn test_within_rebalance_band() {
+    let mut portfolio_info = PortfolioInfo {
+        token_a_percentage: 4500,
+        token_b_percentage: 4500,
+        pc_percentage: 1000,
+        rebalance_band_bps: 100,
+        ..Default::default()
+    };
+    assert!(portfolio_info.within_rebalance_band(4500, 4500, 1000));
+    assert!(portfolio_info.within_rebalance_band(4599, 4401, 1000));
+    assert!(!portfolio_info.within_rebalance_band(4600, 4400, 1000));
+    assert!(!portfolio_info.within_rebalance_band(4500, 4400, 1100));
+
+    // Without a band, any drift is rebalanced.
+    portfolio_info.rebalance_band_bps = 0;
+    assert!(!portfolio_info.within_rebalance_band(4500, 4500, 1000));
+    assert!(!portfolio_info.within_rebalance_band(4501, 4499, 1000));
+}
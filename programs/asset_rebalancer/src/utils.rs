@@ -1,14 +1,14 @@
 use std::num::NonZeroU64;
 
-use crate::constants::VAULT_SIGNER_STR;
+use crate::constants::{MAX_BPS, MAX_SLIPPAGE_BPS, SECONDS_PER_YEAR, VAULT_SIGNER_STR};
 use crate::errors::ErrorCode;
-use crate::instructions::rebalance::{MarketAccounts, Rebalance};
+use crate::instructions::rebalance::{token_a_holding, token_b_holding, MarketAccounts, Rebalance};
 
-use crate::state::PortfolioInfo;
+use crate::state::{Decimal, PortfolioInfo, Rounding};
 use anchor_lang::prelude::*;
-use anchor_spl::dex::serum_dex::instruction::SelfTradeBehavior;
+use anchor_spl::dex::serum_dex::instruction::{msrm_token, srm_token, SelfTradeBehavior};
 use anchor_spl::dex::serum_dex::matching::{OrderType, Side as SerumSide};
-use anchor_spl::dex::serum_dex::state::MarketState;
+use anchor_spl::dex::serum_dex::state::{Market, MarketState};
 use anchor_spl::{dex, token};
 
 pub mod empty {
@@ -16,14 +16,16 @@ pub mod empty {
     declare_id!("HJt8Tjdsc9ms9i4WCZEzhzr4oyf3ANcdzXrNdLPFqm3M");
 }
 
+// Returns the quote currency the first leg sold for, and the spill left over.
 #[access_control(is_valid_swap_transitive(&ctx))]
 pub fn swap_transitive<'info>(
     // ctx: Context<>,
-    ctx: Context<Rebalance<'info>>,
+    ctx: &mut Context<'_, '_, '_, 'info, Rebalance<'info>>,
     swap_direction: SwapDirection,
     amount: u64,
+    redeploy_amount: u64,
     min_exchange_rate: ExchangeRate,
-) -> Result<()> {
+) -> Result<(u64, u64)> {
     let from_coin_wallet = if swap_direction.clone() == SwapDirection::AB {
         ctx.accounts.token_a_market.coin_wallet.clone()
     } else {
@@ -34,6 +36,11 @@ pub fn swap_transitive<'info>(
     } else {
         ctx.accounts.token_a_market.coin_wallet.clone()
     };
+    let fee_accounts = FeeAccounts::load(
+        ctx.remaining_accounts,
+        &ctx.accounts.portfolio_info,
+        ctx.accounts.vault_signer.key,
+    )?;
 
     // Leg 1: Sell Token A for USD(x) (or whatever quote currency is used).
     let (from_amount, sell_proceeds) = {
@@ -42,8 +49,8 @@ pub fn swap_transitive<'info>(
         let quote_before = token::accessor::amount(&ctx.accounts.pc_wallet.to_account_info())?;
         // Execute the trade.
         let orderbook = ctx.accounts.orderbook_from(swap_direction.clone());
-        orderbook.sell(amount, None)?;
-        orderbook.settle(None)?;
+        orderbook.sell(amount, fee_accounts.srm_msrm_discount.clone())?;
+        orderbook.settle(fee_accounts.referral.clone())?;
 
         // Token balances after the trade.
         let base_after = token::accessor::amount(&from_coin_wallet)?;
@@ -52,8 +59,12 @@ pub fn swap_transitive<'info>(
         // Report the delta.
 
         (
-            base_before.checked_sub(base_after).unwrap(),
-            quote_after.checked_sub(quote_before).unwrap(),
+            base_before
+                .checked_sub(base_after)
+                .ok_or(ErrorCode::MathOverflow)?,
+            quote_after
+                .checked_sub(quote_before)
+                .ok_or(ErrorCode::MathOverflow)?,
         )
     };
     msg!("--------------------------------");
@@ -67,23 +78,28 @@ pub fn swap_transitive<'info>(
         from_amount,
         sell_proceeds
     );
-    // Leg 2: Buy Token B with USD(x) (or whatever quote currency is used).
+    // Leg 2: Buy Token B with USD(x) (or whatever quote currency is used),
+    // along with any idle quote the portfolio is redeploying.
     let (to_amount, buy_proceeds) = {
         // Token balances before the trade.
         let base_before = token::accessor::amount(&to_coin_wallet)?;
         let quote_before = token::accessor::amount(&ctx.accounts.pc_wallet.to_account_info())?;
 
-
         // Execute the trade.
-        let orderbook = ctx.accounts.orderbook_to(swap_direction);
+        let orderbook = ctx.accounts.orderbook_to(swap_direction.clone());
         // let amount_to_buy = sell_proceeds
         //     .checked_div(to_price as u64)
         //     .unwrap()
         //     .checked_mul(10u64.checked_pow(to_decimals.into()).unwrap())
         //     .unwrap();
 
-        orderbook.buy(sell_proceeds, None)?;
-        orderbook.settle(None)?;
+        orderbook.buy(
+            sell_proceeds
+                .checked_add(redeploy_amount)
+                .ok_or(ErrorCode::MathOverflow)?,
+            fee_accounts.srm_msrm_discount.clone(),
+        )?;
+        orderbook.settle(fee_accounts.referral.clone())?;
 
         // Token balances after the trade.
         let base_after = token::accessor::amount(&to_coin_wallet)?;
@@ -91,8 +107,12 @@ pub fn swap_transitive<'info>(
 
         // Report the delta.
         (
-            base_after.checked_sub(base_before).unwrap(),
-            quote_before.checked_sub(quote_after).unwrap(),
+            base_after
+                .checked_sub(base_before)
+                .ok_or(ErrorCode::MathOverflow)?,
+            quote_before
+                .checked_sub(quote_after)
+                .ok_or(ErrorCode::MathOverflow)?,
         )
     };
     msg!("--------------------------------");
@@ -108,22 +128,227 @@ pub fn swap_transitive<'info>(
 
     // The amount of surplus quote currency *not* fully consumed by the
     // second half of the swap.
-    let spill_amount = sell_proceeds.checked_sub(buy_proceeds).unwrap();
+    let spill_amount = sell_proceeds
+        .checked_add(redeploy_amount)
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_sub(buy_proceeds)
+        .ok_or(ErrorCode::MathOverflow)?;
 
-    // Safety checks.
+    // Safety checks. Each leg is a market order, so each one is held to the
+    // oracle on its own.
+    check_sale_fill(
+        &ctx.accounts.portfolio_info,
+        &swap_direction,
+        from_amount,
+        sell_proceeds,
+    )?;
+    check_purchase_fill(
+        &ctx.accounts.portfolio_info,
+        &swap_direction,
+        to_amount,
+        buy_proceeds,
+    )?;
     apply_risk_checks(DidSwap {
         given_amount: amount,
         min_exchange_rate,
         from_amount,
         to_amount,
         quote_amount: sell_proceeds,
+        redeployed_amount: redeploy_amount,
         spill_amount,
         from_mint: token::accessor::mint(&from_coin_wallet)?,
         to_mint: token::accessor::mint(&to_coin_wallet)?,
         quote_mint: token::accessor::mint(&ctx.accounts.pc_wallet.to_account_info())?,
         authority: *ctx.accounts.vault_signer.key,
+        fee_tier: fee_accounts.fee_tier()?,
     })?;
 
+    Ok((sell_proceeds, spill_amount))
+}
+
+// Sells `amount` of the *from* token of `swap_direction` into the quote
+// currency and keeps the proceeds, i.e. only the first leg of a transitive
+// swap. Returns the quote currency received.
+pub fn sell_for_quote<'info>(
+    ctx: &mut Context<'_, '_, '_, 'info, Rebalance<'info>>,
+    swap_direction: SwapDirection,
+    amount: u64,
+) -> Result<u64> {
+    let from_coin_wallet = if swap_direction.clone() == SwapDirection::AB {
+        ctx.accounts.token_a_market.coin_wallet.clone()
+    } else {
+        ctx.accounts.token_b_market.coin_wallet.clone()
+    };
+    let fee_accounts = FeeAccounts::load(
+        ctx.remaining_accounts,
+        &ctx.accounts.portfolio_info,
+        ctx.accounts.vault_signer.key,
+    )?;
+
+    let (from_amount, sell_proceeds) = {
+        // Token balances before the trade.
+        let base_before = token::accessor::amount(&from_coin_wallet)?;
+        let quote_before = token::accessor::amount(&ctx.accounts.pc_wallet.to_account_info())?;
+
+        // Execute the trade.
+        let orderbook = ctx.accounts.orderbook_from(swap_direction.clone());
+        orderbook.sell(amount, fee_accounts.srm_msrm_discount.clone())?;
+        orderbook.settle(fee_accounts.referral.clone())?;
+
+        // Token balances after the trade.
+        let base_after = token::accessor::amount(&from_coin_wallet)?;
+        let quote_after = token::accessor::amount(&ctx.accounts.pc_wallet.to_account_info())?;
+
+        // Report the delta.
+        (
+            base_before
+                .checked_sub(base_after)
+                .ok_or(ErrorCode::MathOverflow)?,
+            quote_after
+                .checked_sub(quote_before)
+                .ok_or(ErrorCode::MathOverflow)?,
+        )
+    };
+    msg!(
+        "amount to sell: {}, sold: {}, quote received: {}",
+        amount,
+        from_amount,
+        sell_proceeds
+    );
+
+    emit!(DidSell {
+        given_amount: amount,
+        from_amount,
+        quote_amount: sell_proceeds,
+        from_mint: token::accessor::mint(&from_coin_wallet)?,
+        quote_mint: token::accessor::mint(&ctx.accounts.pc_wallet.to_account_info())?,
+        authority: *ctx.accounts.vault_signer.key,
+        fee_tier: fee_accounts.fee_tier()?,
+    });
+
+    if sell_proceeds == 0 {
+        return Err(ErrorCode::ZeroSwap.into());
+    }
+    check_sale_fill(
+        &ctx.accounts.portfolio_info,
+        &swap_direction,
+        from_amount,
+        sell_proceeds,
+    )?;
+    Ok(sell_proceeds)
+}
+
+// Spends idle quote currency held by the portfolio on the *to* token of
+// `swap_direction`, i.e. only the second leg of a transitive swap. Returns the
+// quote currency spent.
+pub fn buy_with_quote<'info>(
+    ctx: &mut Context<'_, '_, '_, 'info, Rebalance<'info>>,
+    swap_direction: SwapDirection,
+    quote_amount: u64,
+) -> Result<u64> {
+    let to_coin_wallet = if swap_direction.clone() == SwapDirection::AB {
+        ctx.accounts.token_b_market.coin_wallet.clone()
+    } else {
+        ctx.accounts.token_a_market.coin_wallet.clone()
+    };
+    let fee_accounts = FeeAccounts::load(
+        ctx.remaining_accounts,
+        &ctx.accounts.portfolio_info,
+        ctx.accounts.vault_signer.key,
+    )?;
+
+    let (to_amount, quote_spent) = {
+        // Token balances before the trade.
+        let base_before = token::accessor::amount(&to_coin_wallet)?;
+        let quote_before = token::accessor::amount(&ctx.accounts.pc_wallet.to_account_info())?;
+
+        // Execute the trade.
+        let orderbook = ctx.accounts.orderbook_to(swap_direction.clone());
+        orderbook.buy(quote_amount, fee_accounts.srm_msrm_discount.clone())?;
+        orderbook.settle(fee_accounts.referral.clone())?;
+
+        // Token balances after the trade.
+        let base_after = token::accessor::amount(&to_coin_wallet)?;
+        let quote_after = token::accessor::amount(&ctx.accounts.pc_wallet.to_account_info())?;
+
+        // Report the delta.
+        (
+            base_after
+                .checked_sub(base_before)
+                .ok_or(ErrorCode::MathOverflow)?,
+            quote_before
+                .checked_sub(quote_after)
+                .ok_or(ErrorCode::MathOverflow)?,
+        )
+    };
+    msg!(
+        "quote redeployed: {}, quote spent: {}, obtained: {}",
+        quote_amount,
+        quote_spent,
+        to_amount
+    );
+
+    emit!(DidBuy {
+        quote_amount,
+        quote_spent,
+        to_amount,
+        to_mint: token::accessor::mint(&to_coin_wallet)?,
+        quote_mint: token::accessor::mint(&ctx.accounts.pc_wallet.to_account_info())?,
+        authority: *ctx.accounts.vault_signer.key,
+        fee_tier: fee_accounts.fee_tier()?,
+    });
+
+    if to_amount == 0 {
+        return Err(ErrorCode::ZeroSwap.into());
+    }
+    check_purchase_fill(
+        &ctx.accounts.portfolio_info,
+        &swap_direction,
+        to_amount,
+        quote_spent,
+    )?;
+    Ok(quote_spent)
+}
+
+// Market orders have no limit, so a sale of `from_amount` of the *from* token
+// of `swap_direction` must fetch at least its worth at the portfolio's last
+// refreshed price, less `MAX_SLIPPAGE_BPS`.
+fn check_sale_fill(
+    portfolio_info: &PortfolioInfo,
+    swap_direction: &SwapDirection,
+    from_amount: u64,
+    quote_received: u64,
+) -> Result<()> {
+    let sold = match swap_direction {
+        SwapDirection::AB => token_a_holding(portfolio_info, from_amount)?,
+        SwapDirection::BA => token_b_holding(portfolio_info, from_amount)?,
+    };
+    let sold_worth = calculate_quote_amount(sold.worth()?, portfolio_info.pc_decimals)?;
+    require!(
+        quote_received >= calculate_min_received(sold_worth, MAX_SLIPPAGE_BPS)?,
+        ErrorCode::SlippageExceeded
+    );
+    Ok(())
+}
+
+// Likewise, `to_amount` of the *to* token of `swap_direction` bought for
+// `quote_spent` must be worth at least what was spent, less
+// `MAX_SLIPPAGE_BPS`.
+fn check_purchase_fill(
+    portfolio_info: &PortfolioInfo,
+    swap_direction: &SwapDirection,
+    to_amount: u64,
+    quote_spent: u64,
+) -> Result<()> {
+    let bought = match swap_direction {
+        SwapDirection::AB => token_b_holding(portfolio_info, to_amount)?,
+        SwapDirection::BA => token_a_holding(portfolio_info, to_amount)?,
+    };
+    let bought_worth = calculate_quote_amount(bought.worth()?, portfolio_info.pc_decimals)?;
+    require!(
+        bought_worth >= calculate_min_received(quote_spent, MAX_SLIPPAGE_BPS)?,
+        ErrorCode::SlippageExceeded
+    );
     Ok(())
 }
 
@@ -136,103 +361,58 @@ fn apply_risk_checks(event: DidSwap) -> Result<()> {
         return Err(ErrorCode::ZeroSwap.into());
     }
 
-    // Use the exchange rate to calculate the client's expectation.
-    //
-    // The exchange rate given must always have decimals equal to the
-    // `to_mint` decimals, guaranteeing the `min_expected_amount`
-    // always has decimals equal to
-    //
-    // `decimals(from_mint) + decimals(to_mint) + decimals(quote_mint)`.
-    //
-    // We avoid truncating by adding `decimals(quote_mint)`.
-    let min_expected_amount = u128::from(
-        // decimals(from).
-        event.from_amount,
-    )
-    .checked_mul(
-        // decimals(from) + decimals(to).
-        event.min_exchange_rate.rate.into(),
-    )
-    .unwrap()
-    .checked_mul(
-        // decimals(from) + decimals(to) + decimals(quote).
-        10u128
-            .checked_pow(event.min_exchange_rate.quote_decimals.into())
-            .unwrap(),
-    )
-    .unwrap();
+    // Use the exchange rate to calculate the client's expectation, in
+    // native *to* units.
+    let from_decimals = event.min_exchange_rate.from_decimals;
+    let quote_decimals = event.min_exchange_rate.quote_decimals;
+    let min_expected_amount = Decimal::from_token_amount(event.from_amount, from_decimals)
+        .checked_mul(Decimal::new(event.min_exchange_rate.rate.into(), 0))
+        .ok_or(ErrorCode::MathOverflow)?;
 
     // If there is spill (i.e. quote tokens *not* fully consumed for
     // the buy side of a transitive swap), then credit those tokens marked
     // at the executed exchange rate to create an "effective" to_amount.
+    //
+    // Idle quote redeployed by the portfolio is spent alongside the sale
+    // proceeds, so only the share of `to_amount` bought with the proceeds
+    // counts towards the exchange rate.
     let effective_to_amount = {
-        // Translates the leftover spill amount into "to" units via
-        //
-        // `(to_amount_received/quote_amount_given) * spill_amount`
-        //
-        let spill_surplus = match event.spill_amount == 0 || event.min_exchange_rate.strict {
-            true => 0,
-            false => u128::from(
-                // decimals(to).
-                event.to_amount,
-            )
-            .checked_mul(
-                // decimals(to) + decimals(quote).
-                event.spill_amount.into(),
-            )
-            .unwrap()
-            .checked_mul(
-                // decimals(to) + decimals(quote) + decimals(from).
-                10u128
-                    .checked_pow(event.min_exchange_rate.from_decimals.into())
-                    .unwrap(),
-            )
-            .unwrap()
-            .checked_mul(
-                // decimals(to) + decimals(quote)*2 + decimals(from).
-                10u128
-                    .checked_pow(event.min_exchange_rate.quote_decimals.into())
-                    .unwrap(),
+        // The quote currency actually consumed by the buy side.
+        let quote_spent = event
+            .quote_amount
+            .checked_add(event.redeployed_amount)
+            .ok_or(ErrorCode::MathOverflow)?
+            .checked_sub(event.spill_amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        // The part of it paid for with the sale proceeds. When strict, the
+        // spill is ignored rather than marked at the executed exchange rate.
+        let quote_from_sale = match event.min_exchange_rate.strict {
+            true => event.quote_amount.min(quote_spent),
+            false => event.quote_amount,
+        };
+
+        // `(to_amount_received/quote_spent) * quote_from_sale`, kept at the
+        // precision of the expectation so nothing is truncated before the
+        // comparison.
+        Decimal::new(event.to_amount.into(), 0)
+            .checked_mul(Decimal::from_token_amount(quote_from_sale, quote_decimals))
+            .ok_or(ErrorCode::MathOverflow)?
+            .rescale(
+                u32::from(from_decimals) + u32::from(quote_decimals),
+                Rounding::Down,
             )
-            .unwrap()
+            .ok_or(ErrorCode::MathOverflow)?
             .checked_div(
-                // decimals(to) + decimals(quote) + decimals(from).
-                event
-                    .quote_amount
-                    .checked_sub(event.spill_amount)
-                    .unwrap()
-                    .into(),
+                Decimal::from_token_amount(quote_spent, quote_decimals),
+                Rounding::Down,
             )
-            .unwrap(),
-        };
-
-        // Translate the `to_amount` into a common number of decimals.
-        let to_amount = u128::from(
-            // decimals(to).
-            event.to_amount,
-        )
-        .checked_mul(
-            // decimals(to) + decimals(from).
-            10u128
-                .checked_pow(event.min_exchange_rate.from_decimals.into())
-                .unwrap(),
-        )
-        .unwrap()
-        .checked_mul(
-            // decimals(to) + decimals(from) + decimals(quote).
-            10u128
-                .checked_pow(event.min_exchange_rate.quote_decimals.into())
-                .unwrap(),
-        )
-        .unwrap();
-
-        to_amount.checked_add(spill_surplus).unwrap()
+            .ok_or(ErrorCode::MathOverflow)?
     };
 
     // Abort if the resulting amount is less than the client's expectation.
     if effective_to_amount < min_expected_amount {
         msg!(
-            "effective_to_amount, min_expected_amount: {:?}, {:?}",
+            "effective_to_amount, min_expected_amount: {}, {}",
             effective_to_amount,
             min_expected_amount,
         );
@@ -242,6 +422,65 @@ fn apply_risk_checks(event: DidSwap) -> Result<()> {
     Ok(())
 }
 
+// Optional Serum fee accounts, passed to a rebalance as remaining accounts.
+#[derive(Clone, Default)]
+pub struct FeeAccounts<'info> {
+    // SRM or MSRM token account owned by the vault signer, lowering the
+    // taker fee tier of the portfolio's orders.
+    pub srm_msrm_discount: Option<AccountInfo<'info>>,
+    // Quote currency token account earning the referrer rebate on settle.
+    pub referral: Option<AccountInfo<'info>>,
+}
+
+impl<'info> FeeAccounts<'info> {
+    // Sorts the given accounts into a discount and a referral account by mint.
+    // The discount account must be owned by the vault signer, since the dex
+    // only applies it to orders whose open orders owner holds the tokens.
+    pub fn load(
+        accounts: &[AccountInfo<'info>],
+        portfolio_info: &PortfolioInfo,
+        vault_signer: &Pubkey,
+    ) -> Result<Self> {
+        let mut fee_accounts = FeeAccounts::default();
+        for account in accounts {
+            require_keys_eq!(*account.owner, token::ID, ErrorCode::InvalidFeeAccount);
+            let mint = token::accessor::mint(account)?;
+            if mint == srm_token::ID || mint == msrm_token::ID {
+                require!(
+                    fee_accounts.srm_msrm_discount.is_none()
+                        && token::accessor::authority(account)? == *vault_signer,
+                    ErrorCode::InvalidFeeAccount
+                );
+                fee_accounts.srm_msrm_discount = Some(account.clone());
+            } else if mint == portfolio_info.pc_mint {
+                require!(
+                    fee_accounts.referral.is_none(),
+                    ErrorCode::InvalidFeeAccount
+                );
+                fee_accounts.referral = Some(account.clone());
+            } else {
+                return Err(ErrorCode::InvalidFeeAccount.into());
+            }
+        }
+        Ok(fee_accounts)
+    }
+
+    // Returns the dex fee tier the portfolio's orders are charged at.
+    pub fn fee_tier(&self) -> Result<u8> {
+        match &self.srm_msrm_discount {
+            None => Ok(fee_tier(0, 0)),
+            Some(discount) => {
+                let held = token::accessor::amount(discount)?;
+                if token::accessor::mint(discount)? == msrm_token::ID {
+                    Ok(fee_tier(0, held))
+                } else {
+                    Ok(fee_tier(held, 0))
+                }
+            }
+        }
+    }
+}
+
 // Client for sending orders to the Serum DEX.
 #[derive(Clone)]
 pub struct OrderbookClient<'info> {
@@ -273,8 +512,9 @@ impl<'info> OrderbookClient<'info> {
         let limit_price = 1;
         let max_coin_qty = {
             // The loaded market must be dropped before CPI.
-            let market = MarketState::load(&self.market.market, &dex::ID).unwrap();
-            coin_lots(&market, base_amount)
+            let market = MarketState::load(&self.market.market, &dex::ID)
+                .map_err(|_| error!(ErrorCode::InvalidMarket))?;
+            coin_lots(&market, base_amount)?
         };
         let max_native_pc_qty = u64::MAX;
         self.order_cpi(
@@ -345,9 +585,9 @@ impl<'info> OrderbookClient<'info> {
             // ctx,
             ctx.with_signer(&[pda_seeds.as_ref()]),
             side.into(),
-            NonZeroU64::new(limit_price).unwrap(),
-            NonZeroU64::new(max_coin_qty).unwrap(),
-            NonZeroU64::new(max_native_pc_qty).unwrap(),
+            NonZeroU64::new(limit_price).ok_or(ErrorCode::ZeroOrderSize)?,
+            NonZeroU64::new(max_coin_qty).ok_or(ErrorCode::ZeroOrderSize)?,
+            NonZeroU64::new(max_native_pc_qty).ok_or(ErrorCode::ZeroOrderSize)?,
             SelfTradeBehavior::DecrementTake,
             OrderType::ImmediateOrCancel,
             client_order_id,
@@ -380,6 +620,48 @@ impl<'info> OrderbookClient<'info> {
 
         dex::settle_funds(ctx.with_signer(&[pda_seeds.as_ref()]))
     }
+    // Cancels every order the open orders account still has resting on the
+    // book. Orders are placed immediate-or-cancel, so this is normally a
+    // no-op, but anything left behind keeps funds locked and prevents the
+    // open orders account from being closed.
+    pub fn cancel_all(&self) -> Result<()> {
+        let orders: Vec<(SerumSide, u128)> = {
+            // The loaded market and open orders must be dropped before CPI.
+            let market = Market::load(&self.market.market, &dex::ID)
+                .map_err(|_| error!(ErrorCode::InvalidMarket))?;
+            let open_orders = market
+                .load_orders_mut(&self.market.open_orders, None, &dex::ID, None, None)
+                .map_err(|_| error!(ErrorCode::InvalidMarket))?;
+            let order_ids = open_orders.orders;
+            (0..128u8)
+                .filter_map(|slot| {
+                    open_orders
+                        .slot_side(slot)
+                        .map(|side| (side, order_ids[slot as usize]))
+                })
+                .collect()
+        };
+
+        let portfolio_info_key = self.portfolio_info.key();
+        let pda_seeds = &[
+            VAULT_SIGNER_STR.as_bytes(),
+            portfolio_info_key.as_ref(),
+            &[self.portfolio_info.vault_signer_bump],
+        ];
+        for (side, order_id) in orders {
+            let cancel_accs = dex::CancelOrderV2 {
+                market: self.market.market.clone(),
+                market_bids: self.market.bids.clone(),
+                market_asks: self.market.asks.clone(),
+                open_orders: self.market.open_orders.clone(),
+                open_orders_authority: self.authority.clone(),
+                event_queue: self.market.event_queue.clone(),
+            };
+            let ctx = CpiContext::new(self.dex_program.clone(), cancel_accs);
+            dex::cancel_order_v2(ctx.with_signer(&[pda_seeds.as_ref()]), side, order_id)?;
+        }
+        Ok(())
+    }
 }
 
 impl<'info> From<OrderbookClient<'info>> for dex::NewOrderV3<'info> {
@@ -418,14 +700,50 @@ impl From<Side> for SerumSide {
         }
     }
 }
-// Returns the amount of lots for the base currency of a trade with `size`.
-pub fn coin_lots(market: &MarketState, size: u64) -> u64 {
-    size.checked_div(market.coin_lot_size).unwrap()
+// Returns the Serum fee tier for the given native SRM and MSRM holdings, from
+// 0 (base) to 6 (MSRM). Mirrors `FeeTier::from_srm_and_msrm_balances`, which
+// the dex crate doesn't export.
+pub fn fee_tier(srm_held: u64, msrm_held: u64) -> u8 {
+    let one_srm = 1_000_000;
+    match () {
+        () if msrm_held >= 1 => 6,
+        () if srm_held >= one_srm * 1_000_000 => 5,
+        () if srm_held >= one_srm * 100_000 => 4,
+        () if srm_held >= one_srm * 10_000 => 3,
+        () if srm_held >= one_srm * 1_000 => 2,
+        () if srm_held >= one_srm * 100 => 1,
+        () => 0,
+    }
 }
-#[derive(AnchorSerialize, AnchorDeserialize)]
-pub struct VaultSignerSeeds {
-    pub portfolio_info_key: Pubkey,
-    pub vault_signer_bump: u8,
+
+// Returns the native size of one lot of `market`'s base currency, i.e. the
+// smallest amount an order can be placed for.
+pub fn coin_lot_size(market: &AccountInfo) -> Result<u64> {
+    let market =
+        MarketState::load(market, &dex::ID).map_err(|_| error!(ErrorCode::InvalidMarket))?;
+    Ok(market.coin_lot_size)
+}
+
+// Returns the base and quote mints `market` trades.
+pub fn market_mints(market: &AccountInfo) -> Result<(Pubkey, Pubkey)> {
+    let market =
+        MarketState::load(market, &dex::ID).map_err(|_| error!(ErrorCode::InvalidMarket))?;
+    // The dex stores pubkeys as little endian words.
+    let to_pubkey = |words: [u64; 4]| {
+        let mut bytes = [0u8; 32];
+        for (chunk, word) in bytes.chunks_exact_mut(8).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        Pubkey::new_from_array(bytes)
+    };
+    Ok((to_pubkey(market.coin_mint), to_pubkey(market.pc_mint)))
+}
+
+// Returns the amount of lots for the base currency of a trade with `size`.
+pub fn coin_lots(market: &MarketState, size: u64) -> Result<u64> {
+    Ok(size
+        .checked_div(market.coin_lot_size)
+        .ok_or(ErrorCode::InvalidMarket)?)
 }
 // An exchange rate for swapping *from* one token *to* another.
 #[derive(AnchorSerialize, AnchorDeserialize)]
@@ -475,9 +793,12 @@ pub struct DidSwap {
     // The amount of the quote currency used for a *transitive* swap. This is
     // the amount *received* for selling on the first leg of the swap.
     pub quote_amount: u64,
+    // Amount of idle quote currency already held by the portfolio that was
+    // added to the second leg of the swap.
+    pub redeployed_amount: u64,
     // Amount of the quote currency accumulated from a *transitive* swap, i.e.,
-    // the difference between the amount gained from the first leg of the swap
-    // (to sell) and the amount used in the second leg of the swap (to buy).
+    // the difference between the amount available to the second leg of the
+    // swap (sale proceeds plus redeployed quote) and the amount used to buy.
     pub spill_amount: u64,
     // Mint sold.
     pub from_mint: Pubkey,
@@ -488,6 +809,45 @@ pub struct DidSwap {
     pub quote_mint: Pubkey,
     // User that signed the transaction.
     pub authority: Pubkey,
+    // Serum fee tier the orders were charged at, as determined by the
+    // SRM/MSRM discount account (0 is the base tier).
+    pub fee_tier: u8,
+}
+
+#[event]
+pub struct DidSell {
+    // Given (max) amount of the `from` token to sell.
+    pub given_amount: u64,
+    // Amount of the `from` token sold.
+    pub from_amount: u64,
+    // Amount of the quote currency received and kept by the portfolio.
+    pub quote_amount: u64,
+    // Mint sold.
+    pub from_mint: Pubkey,
+    // Mint of the quote currency received.
+    pub quote_mint: Pubkey,
+    // Authority the order was placed for.
+    pub authority: Pubkey,
+    // Serum fee tier the order was charged at.
+    pub fee_tier: u8,
+}
+
+#[event]
+pub struct DidBuy {
+    // Amount of idle quote currency offered on the buy side.
+    pub quote_amount: u64,
+    // Amount of the quote currency actually used to buy.
+    pub quote_spent: u64,
+    // Amount of the `to` token purchased.
+    pub to_amount: u64,
+    // Mint purchased.
+    pub to_mint: Pubkey,
+    // Mint of the quote currency spent.
+    pub quote_mint: Pubkey,
+    // Authority the order was placed for.
+    pub authority: Pubkey,
+    // Serum fee tier the order was charged at.
+    pub fee_tier: u8,
 }
 
 fn is_valid_swap_transitive<'info>(ctx: &Context<Rebalance>) -> Result<()> {
@@ -507,31 +867,550 @@ fn _is_valid_swap<'info>(from: &AccountInfo<'info>, to: &AccountInfo<'info>) ->
     Ok(())
 }
 
+// A native token balance and the price it is valued at.
+#[derive(Clone, Copy)]
+pub struct Holding {
+    pub amount: u64,
+    pub decimals: u8,
+    pub price: Decimal,
+}
+
+impl Holding {
+    // The quote currency is valued at 1.0.
+    pub fn quote(amount: u64, decimals: u8) -> Self {
+        Holding {
+            amount,
+            decimals,
+            price: Decimal::new(1, 0),
+        }
+    }
+
+    // Exact worth of the holding, i.e., `amount * price`.
+    pub fn worth(&self) -> Result<Decimal> {
+        Ok(Decimal::from_token_amount(self.amount, self.decimals)
+            .checked_mul(self.price)
+            .ok_or(ErrorCode::MathOverflow)?)
+    }
+}
+
+// Worth of each sleeve of the vault and the vault's total worth (its net
+// asset value), all at the same decimals.
+#[derive(Clone, Copy)]
+pub struct VaultValuation {
+    pub token_a_worth: Decimal,
+    pub token_b_worth: Decimal,
+    pub pc_worth: Decimal,
+    pub total_worth: Decimal,
+}
+
+// Values native balances of token a, token b and the quote currency. Worths
+// are expressed at the largest decimals any holding needs, so fractional
+// balances and prices are kept exactly.
+pub fn calculate_vault_worth(
+    token_a: &Holding,
+    token_b: &Holding,
+    pc: &Holding,
+) -> Result<VaultValuation> {
+    let token_a_worth = token_a.worth()?;
+    let token_b_worth = token_b.worth()?;
+    let pc_worth = pc.worth()?;
+    let total_worth = token_a_worth
+        .checked_add(token_b_worth)
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_add(pc_worth)
+        .ok_or(ErrorCode::MathOverflow)?;
+
+    let decimals = total_worth.decimals;
+    let at_common_decimals = |worth: Decimal| {
+        worth
+            .rescale(decimals, Rounding::Down)
+            .ok_or(ErrorCode::MathOverflow)
+    };
+    Ok(VaultValuation {
+        token_a_worth: at_common_decimals(token_a_worth)?,
+        token_b_worth: at_common_decimals(token_b_worth)?,
+        pc_worth: at_common_decimals(pc_worth)?,
+        total_worth,
+    })
+}
+
+// Converts a worth into a native amount of the quote currency. A negative
+// worth, e.g. a quote sleeve short of the fees it owes, converts to nothing.
+pub fn calculate_quote_amount(worth: Decimal, pc_decimals: u8) -> Result<u64> {
+    Ok(worth
+        .max(Decimal::zero())
+        .to_token_amount(pc_decimals, Rounding::Down)
+        .ok_or(ErrorCode::MathOverflow)?)
+}
+
+// Worths are divided at no more than this many decimals. Priced at Pyth's
+// usual 8 decimals, a token with 9 carries 17, and `Decimal::checked_div`
+// scaling the numerator up by as many again would overflow an i128.
+const DIVISION_DECIMALS: u32 = 6;
+
+// `worth` rounded down to at most `DIVISION_DECIMALS`, to divide or divide by.
+fn at_division_precision(worth: Decimal) -> Result<Decimal> {
+    Ok(worth
+        .rescale(worth.decimals.min(DIVISION_DECIMALS), Rounding::Down)
+        .ok_or(ErrorCode::MathOverflow)?)
+}
+
+// Returns whether `total_vault_worth` is too little to divide by, i.e. nothing
+// or less than the smallest worth kept when dividing.
+pub fn is_negligible_worth(total_vault_worth: Decimal) -> Result<bool> {
+    Ok(at_division_precision(total_vault_worth)? <= Decimal::zero())
+}
+
+// Returns the share of `asset_worth` in `total_vault_worth`, in basis points.
+// A negative worth has no share.
 pub fn calculate_assets_percentage_worth_in_vault(
-    token_a_amount: u64,
-    token_a_price: u64,
-    token_b_amount: u64,
-    token_b_price: u64,
-) -> u16 {
-    let token_a_worth = u128::from(token_a_amount)
-        .checked_mul(token_a_price.into())
-        .unwrap();
-    let token_b_worth = u128::from(token_b_amount)
-        .checked_mul(token_b_price.into())
-        .unwrap();
-    let total_vault_worth = token_a_worth.checked_add(token_b_worth).unwrap();
-
-    // calculate token a percentage
-    let token_a_percentage = token_a_worth
-        .checked_mul(1000)
-        .unwrap()
-        .checked_div(total_vault_worth)
-        .unwrap();
-    u16::from(token_a_percentage as u16)
+    asset_worth: Decimal,
+    total_vault_worth: Decimal,
+) -> Result<u16> {
+    let total_vault_worth = at_division_precision(total_vault_worth)?;
+    let percentage = at_division_precision(asset_worth.max(Decimal::zero()))?
+        .checked_mul(Decimal::new(MAX_BPS.into(), 0))
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_div(total_vault_worth, Rounding::Down)
+        .ok_or(ErrorCode::MathOverflow)?
+        .rescale(0, Rounding::Down)
+        .ok_or(ErrorCode::MathOverflow)?;
+    u16::try_from(percentage.value).map_err(|_| error!(ErrorCode::MathOverflow))
+}
+
+// Returns the worth an asset should have to make up `target_percentage` basis
+// points of `total_vault_worth`.
+pub fn calculate_target_worth(
+    total_vault_worth: Decimal,
+    target_percentage: u16,
+) -> Result<Decimal> {
+    Ok(total_vault_worth
+        .checked_mul(Decimal::new(target_percentage.into(), 0))
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_div(Decimal::new(MAX_BPS.into(), 0), Rounding::Down)
+        .ok_or(ErrorCode::MathOverflow)?)
+}
+
+// Returns how much of `amount`, worth `asset_worth` in total, has to be sold
+// for the remainder to be worth exactly its target share of the vault.
+pub fn calculate_amount_to_sell(
+    amount: u64,
+    asset_worth: Decimal,
+    total_vault_worth: Decimal,
+    target_percentage: u16,
+) -> Result<u64> {
+    let target_worth = calculate_target_worth(total_vault_worth, target_percentage)?;
+    let excess_worth = at_division_precision(
+        asset_worth
+            .checked_sub(target_worth)
+            .ok_or(ErrorCode::MathOverflow)?
+            .max(Decimal::zero()),
+    )?;
+    let asset_worth = at_division_precision(asset_worth)?;
+    if excess_worth.is_zero() || asset_worth.is_zero() {
+        return Ok(0);
+    }
+    Ok(Decimal::new(amount.into(), 0)
+        .checked_mul(excess_worth)
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_div(asset_worth, Rounding::Down)
+        .ok_or(ErrorCode::MathOverflow)?
+        .to_token_amount(0, Rounding::Down)
+        .ok_or(ErrorCode::MathOverflow)?)
+}
+
+// Returns how much of `amount`, worth `asset_worth` in total, is worth
+// `worth`, at most all of it.
+pub fn calculate_amount_for_worth(
+    amount: u64,
+    asset_worth: Decimal,
+    worth: Decimal,
+    rounding: Rounding,
+) -> Result<u64> {
+    let worth = at_division_precision(worth)?;
+    let asset_worth = at_division_precision(asset_worth)?;
+    if worth.is_zero() || asset_worth.is_zero() {
+        return Ok(0);
+    }
+    if worth >= asset_worth {
+        return Ok(amount);
+    }
+    Ok(Decimal::new(amount.into(), 0)
+        .checked_mul(worth)
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_div(asset_worth, rounding)
+        .ok_or(ErrorCode::MathOverflow)?
+        .to_token_amount(0, rounding)
+        .ok_or(ErrorCode::MathOverflow)?)
+}
+
+// Returns the least a trade giving up `given_worth` should get back, both in
+// native quote units at oracle prices, losing at most `slippage_bps` of it.
+pub fn calculate_min_received(given_worth: u64, slippage_bps: u16) -> Result<u64> {
+    let min_received = u128::from(given_worth)
+        .checked_mul(MAX_BPS.saturating_sub(slippage_bps).into())
+        .ok_or(ErrorCode::MathOverflow)?
+        / u128::from(MAX_BPS);
+    u64::try_from(min_received).map_err(|_| error!(ErrorCode::MathOverflow))
+}
+
+// Returns the keeper's cut of `traded_quote`, `keeper_fee_bps` basis points of
+// it rounded down.
+pub fn calculate_keeper_fee(traded_quote: u64, keeper_fee_bps: u16) -> Result<u64> {
+    let fee = u128::from(traded_quote)
+        .checked_mul(keeper_fee_bps.into())
+        .ok_or(ErrorCode::MathOverflow)?
+        / u128::from(MAX_BPS);
+    u64::try_from(fee).map_err(|_| error!(ErrorCode::MathOverflow))
+}
+
+// Returns the management fee accrued on `net_asset_value` over `elapsed`
+// seconds at an annual rate of `management_fee_bps`, rounded down.
+pub fn calculate_management_fee(
+    net_asset_value: u64,
+    management_fee_bps: u16,
+    elapsed: i64,
+) -> Result<u64> {
+    let elapsed = u128::try_from(elapsed).map_err(|_| error!(ErrorCode::MathOverflow))?;
+    let fee = u128::from(net_asset_value)
+        .checked_mul(management_fee_bps.into())
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_mul(elapsed)
+        .ok_or(ErrorCode::MathOverflow)?
+        / (u128::from(MAX_BPS) * SECONDS_PER_YEAR as u128);
+    // Never more than the portfolio is worth, however long fees went
+    // uncollected.
+    Ok(u64::try_from(fee).unwrap_or(u64::MAX).min(net_asset_value))
+}
+
+// Returns the performance fee on `net_asset_value` above `high_water_mark`,
+// rounded down.
+pub fn calculate_performance_fee(
+    net_asset_value: u64,
+    high_water_mark: u64,
+    performance_fee_bps: u16,
+) -> Result<u64> {
+    let gain = net_asset_value.saturating_sub(high_water_mark);
+    let fee = u128::from(gain)
+        .checked_mul(performance_fee_bps.into())
+        .ok_or(ErrorCode::MathOverflow)?
+        / u128::from(MAX_BPS);
+    u64::try_from(fee).map_err(|_| error!(ErrorCode::MathOverflow))
+}
+
+// Returns `value` less `cost`, which may be negative.
+pub fn calculate_pnl(value: u64, cost: u64) -> Result<i64> {
+    i64::try_from(i128::from(value) - i128::from(cost)).map_err(|_| error!(ErrorCode::MathOverflow))
+}
+
+// Returns an asset's cost basis after its vault balance went from
+// `amount_before` to `amount_after`, and the PnL realized on the way.
+// `traded_worth` is what the units sold or bought are worth in native quote
+// units. Units bought add their worth to the cost basis, units sold take out
+// their average cost and realize the difference.
+pub fn calculate_cost_basis_after_trade(
+    cost_basis: u64,
+    amount_before: u64,
+    amount_after: u64,
+    traded_worth: u64,
+) -> Result<(u64, i64)> {
+    if amount_after >= amount_before {
+        let cost_basis = cost_basis
+            .checked_add(traded_worth)
+            .ok_or(ErrorCode::MathOverflow)?;
+        return Ok((cost_basis, 0));
+    }
+    let cost_sold = u128::from(cost_basis)
+        .checked_mul((amount_before - amount_after).into())
+        .ok_or(ErrorCode::MathOverflow)?
+        / u128::from(amount_before);
+    // Never more than the cost basis, as no more than the balance is sold.
+    let cost_sold = u64::try_from(cost_sold).map_err(|_| error!(ErrorCode::MathOverflow))?;
+    Ok((
+        cost_basis - cost_sold,
+        calculate_pnl(traded_worth, cost_sold)?,
+    ))
 }
 
 #[test]
 pub fn test_percentage_calc() {
-    let value = calculate_assets_percentage_worth_in_vault(5, 1250, 3, 200);
+    let valuation = calculate_vault_worth(
+        &Holding {
+            amount: 5_000_000,
+            decimals: 6,
+            price: Decimal::from_mantissa(1250, -2).unwrap(),
+        },
+        &Holding {
+            amount: 3_000_000,
+            decimals: 6,
+            price: Decimal::from_mantissa(200, -2).unwrap(),
+        },
+        &Holding::quote(0, 6),
+    )
+    .unwrap();
+    let value =
+        calculate_assets_percentage_worth_in_vault(valuation.token_a_worth, valuation.total_worth)
+            .unwrap();
     print!("{}", value);
 }
+
+#[test]
+pub fn test_vault_worth_values_quote_at_one() {
+    // 2.5 A (9 decimals) at 1.50 (expo -2), 0.75 B (6 decimals) at 3.000
+    // (expo -3) and 4.5 of a quote currency with 6 decimals.
+    let valuation = calculate_vault_worth(
+        &Holding {
+            amount: 2_500_000_000,
+            decimals: 9,
+            price: Decimal::from_mantissa(150, -2).unwrap(),
+        },
+        &Holding {
+            amount: 750_000,
+            decimals: 6,
+            price: Decimal::from_mantissa(3000, -3).unwrap(),
+        },
+        &Holding::quote(4_500_000, 6),
+    )
+    .unwrap();
+    assert_eq!(valuation.total_worth.decimals, 11);
+    assert_eq!(valuation.token_a_worth.to_string(), "3.75000000000");
+    assert_eq!(valuation.token_b_worth.to_string(), "2.25000000000");
+    assert_eq!(valuation.pc_worth.to_string(), "4.50000000000");
+    assert_eq!(valuation.total_worth.to_string(), "10.50000000000");
+    assert_eq!(
+        calculate_assets_percentage_worth_in_vault(valuation.pc_worth, valuation.total_worth)
+            .unwrap(),
+        4285
+    );
+}
+
+#[test]
+pub fn test_weights_with_pyth_precision() {
+    // 1 SOL (9 decimals) at 20.00000000 and 1 WETH (8 decimals) at
+    // 1800.00000000, both priced at Pyth's expo of -8.
+    let sol = Holding {
+        amount: 1_000_000_000,
+        decimals: 9,
+        price: Decimal::from_mantissa(2_000_000_000, -8).unwrap(),
+    };
+    let weth = Holding {
+        amount: 100_000_000,
+        decimals: 8,
+        price: Decimal::from_mantissa(180_000_000_000, -8).unwrap(),
+    };
+    let valuation = calculate_vault_worth(&sol, &weth, &Holding::quote(0, 6)).unwrap();
+    assert_eq!(valuation.total_worth.decimals, 17);
+    assert_eq!(
+        calculate_assets_percentage_worth_in_vault(valuation.token_a_worth, valuation.total_worth)
+            .unwrap(),
+        109
+    );
+    assert_eq!(
+        calculate_assets_percentage_worth_in_vault(valuation.token_b_worth, valuation.total_worth)
+            .unwrap(),
+        9890
+    );
+
+    // 1000 of each, as in the integration tests, with a 30/70 target: 526,000
+    // of the 1,800,000 the WETH is worth is above its target.
+    let sol = Holding {
+        amount: 1_000_000_000_000,
+        ..sol
+    };
+    let weth = Holding {
+        amount: 100_000_000_000,
+        ..weth
+    };
+    let valuation = calculate_vault_worth(&sol, &weth, &Holding::quote(0, 6)).unwrap();
+    assert_eq!(
+        calculate_amount_to_sell(
+            weth.amount,
+            valuation.token_b_worth,
+            valuation.total_worth,
+            7000,
+        )
+        .unwrap(),
+        29_222_222_222
+    );
+    assert_eq!(
+        calculate_amount_to_sell(
+            sol.amount,
+            valuation.token_a_worth,
+            valuation.total_worth,
+            3000,
+        )
+        .unwrap(),
+        0
+    );
+}
+
+#[test]
+pub fn test_amount_to_sell_reaches_target() {
+    let worth = |value: i128| Decimal::new(value, 2);
+    // 10 A worth 500 of a 1000 vault, with a 30% target: 4 A worth 200 go.
+    assert_eq!(
+        calculate_amount_to_sell(10, worth(50_000), worth(100_000), 3000).unwrap(),
+        4
+    );
+    // 2.5 A (6 decimals) worth 500 of a 2000 vault, with a 12.5% target.
+    assert_eq!(
+        calculate_amount_to_sell(2_500_000, worth(50_000), worth(200_000), 1250).unwrap(),
+        1_250_000
+    );
+    // Already at or below target.
+    assert_eq!(
+        calculate_amount_to_sell(10, worth(30_000), worth(100_000), 3000).unwrap(),
+        0
+    );
+}
+
+#[test]
+pub fn test_quote_amount_for_worth() {
+    // 12.5 at 9 decimals, in a quote currency with 6 decimals.
+    assert_eq!(
+        calculate_quote_amount(Decimal::new(12_500_000_000, 9), 6).unwrap(),
+        12_500_000
+    );
+    // Dust below the quote currency's decimals is dropped.
+    assert_eq!(
+        calculate_quote_amount(Decimal::new(1_999_999_999, 9), 6).unwrap(),
+        1_999_999
+    );
+    assert_eq!(calculate_quote_amount(Decimal::new(0, 17), 6).unwrap(), 0);
+}
+
+#[test]
+pub fn test_fee_tier() {
+    assert_eq!(fee_tier(0, 0), 0);
+    assert_eq!(fee_tier(99_000_000, 0), 0);
+    assert_eq!(fee_tier(100_000_000, 0), 1);
+    assert_eq!(fee_tier(100_000_000_000, 0), 4);
+    assert_eq!(fee_tier(0, 1), 6);
+}
+
+#[test]
+pub fn test_keeper_fee() {
+    assert_eq!(calculate_keeper_fee(1_000_000, 10).unwrap(), 1_000);
+    assert_eq!(calculate_keeper_fee(999, 10).unwrap(), 0);
+    assert_eq!(calculate_keeper_fee(u64::MAX, MAX_BPS).unwrap(), u64::MAX);
+    assert_eq!(calculate_keeper_fee(1_000_000, 0).unwrap(), 0);
+}
+
+#[test]
+pub fn test_min_received() {
+    assert_eq!(calculate_min_received(1_000_000, 100).unwrap(), 990_000);
+    assert_eq!(calculate_min_received(1_000_000, 0).unwrap(), 1_000_000);
+    assert_eq!(calculate_min_received(999, 100).unwrap(), 989);
+}
+
+#[test]
+pub fn test_management_fee() {
+    // 2% a year on 1000 for half a year.
+    assert_eq!(
+        calculate_management_fee(1_000_000_000, 200, SECONDS_PER_YEAR / 2).unwrap(),
+        10_000_000
+    );
+    assert_eq!(calculate_management_fee(1_000_000_000, 200, 0).unwrap(), 0);
+    assert_eq!(
+        calculate_management_fee(1_000, MAX_BPS, SECONDS_PER_YEAR * 10).unwrap(),
+        1_000
+    );
+    assert!(calculate_management_fee(1_000, 200, -1).is_err());
+}
+
+#[test]
+pub fn test_performance_fee() {
+    // 20% of a 100 gain over the high-water mark.
+    assert_eq!(
+        calculate_performance_fee(1_100_000_000, 1_000_000_000, 2_000).unwrap(),
+        20_000_000
+    );
+    assert_eq!(
+        calculate_performance_fee(900_000_000, 1_000_000_000, 2_000).unwrap(),
+        0
+    );
+}
+
+#[test]
+pub fn test_cost_basis_after_trade() {
+    // Buying 50 units worth 600 on top of 100 units that cost 1000.
+    assert_eq!(
+        calculate_cost_basis_after_trade(1_000, 100, 150, 600).unwrap(),
+        (1_600, 0)
+    );
+    // Selling a quarter of them for 500 realizes a gain over their 250 cost.
+    assert_eq!(
+        calculate_cost_basis_after_trade(1_000, 100, 75, 500).unwrap(),
+        (750, 250)
+    );
+    // And for 200, a loss.
+    assert_eq!(
+        calculate_cost_basis_after_trade(1_000, 100, 75, 200).unwrap(),
+        (750, -50)
+    );
+    // Selling everything takes out the whole cost basis.
+    assert_eq!(
+        calculate_cost_basis_after_trade(1_000, 100, 0, 1_000).unwrap(),
+        (0, 0)
+    );
+}
+
+#[test]
+pub fn test_amount_for_worth() {
+    // 1_000 SOL at 20.0 USDC, with 9 decimals.
+    let sol = Holding {
+        amount: 1_000_000_000_000,
+        decimals: 9,
+        price: Decimal::from_mantissa(2_000_000_000, -8).unwrap(),
+    };
+    let worth = sol.worth().unwrap();
+    let usdc = |amount| Decimal::from_token_amount(amount, 6);
+    let amount_for = |quote, rounding| {
+        calculate_amount_for_worth(sol.amount, worth, usdc(quote), rounding).unwrap()
+    };
+    assert_eq!(amount_for(150_000_000, Rounding::Down), 7_500_000_000);
+    assert_eq!(amount_for(150_000_001, Rounding::Down), 7_500_000_050);
+    assert_eq!(amount_for(1, Rounding::Down), 50);
+    assert_eq!(amount_for(1, Rounding::Up), 50);
+    // Never more than the holding.
+    assert_eq!(amount_for(30_000_000_000, Rounding::Up), sol.amount);
+    assert_eq!(amount_for(0, Rounding::Up), 0);
+    assert_eq!(
+        calculate_amount_for_worth(0, Decimal::zero(), usdc(1), Rounding::Up).unwrap(),
+        0
+    );
+
+    // Negative worths count as nothing.
+    assert_eq!(
+        calculate_quote_amount(usdc(5).checked_sub(usdc(7)).unwrap(), 6).unwrap(),
+        0
+    );
+    assert_eq!(
+        calculate_assets_percentage_worth_in_vault(
+            usdc(5).checked_sub(usdc(7)).unwrap(),
+            usdc(100)
+        )
+        .unwrap(),
+        0
+    );
+}
+
+#[test]
+pub fn test_negligible_worth() {
+    let usdc = |amount| Decimal::from_token_amount(amount, 6);
+    assert!(is_negligible_worth(Decimal::zero()).unwrap());
+    assert!(is_negligible_worth(usdc(1).checked_sub(usdc(2)).unwrap()).unwrap());
+    assert!(!is_negligible_worth(usdc(1)).unwrap());
+
+    // A dust-only vault worth less than 1e-6, e.g. 1 lamport at 20.0 USDC.
+    let dust = Holding {
+        amount: 1,
+        decimals: 9,
+        price: Decimal::from_mantissa(2_000_000_000, -8).unwrap(),
+    }
+    .worth()
+    .unwrap();
+    assert!(dust > Decimal::zero());
+    assert!(is_negligible_worth(dust).unwrap());
+    assert!(calculate_assets_percentage_worth_in_vault(dust, dust).is_err());
+}
@@ -8,10 +8,14 @@ export const TOKEN_B_VAULT_STR = "token_b_vault";
 export const PC_VAULT_STR = "pc_vault";
 export const OPEN_ORDERS_A_STR = "open_orders_a";
 export const OPEN_ORDERS_B_STR = "open_orders_b";
+export const GLOBAL_CONFIG_STR = "global_config";
+export const REBALANCE_HISTORY_STR = "rebalance_history";
 
 
 
 
+export const BPF_LOADER_UPGRADEABLE_PROGRAM_ID = new anchor.web3.PublicKey("BPFLoaderUpgradeab1e11111111111111111111111");
+
 export const PYTH_PROGRAM_ID = new anchor.web3.PublicKey("HEvSKofvBgfaexv23kMabbYqxasxU3mQ4ibBMEmJWHny");
 
 export const PYTH_SOL_PRICE_ACCOUNT = new anchor.web3.PublicKey("H6ARHf6YXhGYeQfUzQNGk6rDNnLBQKrenN712K4AQJEG");
@@ -6,8 +6,11 @@ import { AssetRebalancer } from "../target/types/asset_rebalancer";
 import { createAssociatedTokenAccountAndMintTo, getKeypair, getPortfolioInfoAndSigner, getVaultOwnerAndNonce, mintInfo, mintTokens, mintTokensWithDefaultAuthority, sleep, wrapSol } from "../test_utils/helpers";
 import { getAssociatedTokenAddress, NATIVE_MINT, RawMint } from "@solana/spl-token";
 import {
+  BPF_LOADER_UPGRADEABLE_PROGRAM_ID,
+  GLOBAL_CONFIG_STR,
   OPEN_ORDERS_A_STR,
   OPEN_ORDERS_B_STR,
+  REBALANCE_HISTORY_STR,
 
   PYTH_ETH_PRICE_ACCOUNT,
   PYTH_SOL_PRICE_ACCOUNT,
@@ -24,8 +27,9 @@ let user: Keypair;
 let wSolAccount: anchor.web3.PublicKey;
 let wethAccount: anchor.web3.PublicKey;
 let usdcAccount: anchor.web3.PublicKey;
-let solPercentage = 300;
-let wEthPercentage = 700;
+let solPercentage = 3000;
+let wEthPercentage = 7000;
+let pcPercentage = 0;
 
 
 let wSolVault: anchor.web3.PublicKey;
@@ -33,6 +37,8 @@ let wEthVault: anchor.web3.PublicKey;
 let usdcVault: anchor.web3.PublicKey;
 
 let portfolioInfo: anchor.web3.PublicKey;
+let config: anchor.web3.PublicKey;
+let rebalanceHistory: anchor.web3.PublicKey;
 let vaultSigner: anchor.web3.PublicKey;
 let vaultSignerBump: number;
 
@@ -56,6 +62,18 @@ let vaultOpenOrderBAccount;
 
 let orderBook: OrderBook;
 
+// A second portfolio, onboarded with `createPortfolio` alone.
+let creator: Keypair;
+let creatorWSolAccount: anchor.web3.PublicKey;
+let creatorWethAccount: anchor.web3.PublicKey;
+let creatorUsdcAccount: anchor.web3.PublicKey;
+let createdPortfolioInfo: anchor.web3.PublicKey;
+let createdVaultSigner: anchor.web3.PublicKey;
+let createdVaults: anchor.web3.PublicKey[];
+let createdOpenOrdersA: [anchor.web3.PublicKey, number];
+let createdOpenOrdersB: [anchor.web3.PublicKey, number];
+let createdRebalanceHistory: anchor.web3.PublicKey;
+
 let USDC_SCALER: number;
 let WETH_SCALER: number;
 let SOL_SCALER: number;
@@ -68,6 +86,63 @@ describe("asset_rebalancer", () => {
 
   const program = anchor.workspace.AssetRebalancer as Program<AssetRebalancer>;
 
+  // Onboards the creator's portfolio, trading on the given markets.
+  const createCreatorPortfolio = (marketA: anchor.web3.PublicKey, marketB: anchor.web3.PublicKey) => program.methods
+    .createPortfolio(solPercentage, wEthPercentage, pcPercentage)
+    .accounts({
+      deposit: {
+        tokenAMint: WSOL_MINT,
+        userTokenAAccount: creatorWSolAccount,
+        tokenAVault: createdVaults[0],
+        tokenBMint: WETH_MINT,
+        userTokenBAccount: creatorWethAccount,
+        tokenBVault: createdVaults[1],
+        pcVault: createdVaults[2],
+        pcMint: USDC_MINT,
+        vaultSigner: createdVaultSigner,
+        tokenAPythPrice: PYTH_SOL_PRICE_ACCOUNT,
+        tokenBPythPrice: PYTH_ETH_PRICE_ACCOUNT,
+        portfolioInfo: createdPortfolioInfo,
+        rebalanceHistory: createdRebalanceHistory,
+        config,
+        user: creator.publicKey,
+        payer: creator.publicKey,
+      },
+      openOrdersA: createdOpenOrdersA[0],
+      openOrdersB: createdOpenOrdersB[0],
+      marketA,
+      marketB,
+      dexProgram: serumDexV3,
+    })
+    .signers([creator])
+    .rpc();
+
+
+  // Closes the creator's portfolio, sweeping the markets passed as remaining
+  // accounts.
+  const closeCreatorPortfolio = (remainingAccounts: anchor.web3.AccountMeta[]) => program.methods
+    .closePortfolio()
+    .accounts({
+      tokenAVault: createdVaults[0],
+      tokenBVault: createdVaults[1],
+      pcVault: createdVaults[2],
+      userTokenAAccount: creatorWSolAccount,
+      userTokenBAccount: creatorWethAccount,
+      userPcAccount: creatorUsdcAccount,
+      tokenAPythPrice: PYTH_SOL_PRICE_ACCOUNT,
+      tokenBPythPrice: PYTH_ETH_PRICE_ACCOUNT,
+      feeTokenAWallet: creatorWSolAccount,
+      feeTokenBWallet: creatorWethAccount,
+      feePcWallet: creatorUsdcAccount,
+      vaultSigner: createdVaultSigner,
+      portfolioInfo: createdPortfolioInfo,
+      rebalanceHistory: createdRebalanceHistory,
+      config,
+      user: creator.publicKey,
+    })
+    .remainingAccounts(remainingAccounts)
+    .signers([creator])
+    .rpc();
 
   before(async () => {
 
@@ -134,13 +209,74 @@ describe("asset_rebalancer", () => {
 
 
     vaultOpenOrderAAccount = await anchor.web3.PublicKey.findProgramAddress(
-      [Buffer.from(OPEN_ORDERS_A_STR), vaultSigner.toBuffer()], program.programId,
+      [Buffer.from(OPEN_ORDERS_A_STR), portfolioInfo.toBuffer()], program.programId,
     )
     vaultOpenOrderBAccount = await anchor.web3.PublicKey.findProgramAddress(
-      [Buffer.from(OPEN_ORDERS_B_STR), vaultSigner.toBuffer()], program.programId,
+      [Buffer.from(OPEN_ORDERS_B_STR), portfolioInfo.toBuffer()], program.programId,
     )
+    config = (await anchor.web3.PublicKey.findProgramAddress(
+      [Buffer.from(GLOBAL_CONFIG_STR)], program.programId,
+    ))[0]
+    rebalanceHistory = (await anchor.web3.PublicKey.findProgramAddress(
+      [Buffer.from(REBALANCE_HISTORY_STR), portfolioInfo.toBuffer()], program.programId,
+    ))[0]
 
   })
+  it("initializes the global config", async () => {
+    const programData = (await anchor.web3.PublicKey.findProgramAddress(
+      [program.programId.toBuffer()], BPF_LOADER_UPGRADEABLE_PROGRAM_ID,
+    ))[0];
+    const initConfig = (admin: Keypair | null) => program.methods
+      .initConfig()
+      .accounts({
+        config,
+        admin: admin ? admin.publicKey : provider.wallet.publicKey,
+        program: program.programId,
+        programData,
+      })
+      .signers(admin ? [admin] : [])
+      .rpc();
+
+    // Only the upgrade authority can claim the admin role.
+    const impostor = Keypair.generate();
+    await program.provider.sendAndConfirm(new anchor.web3.Transaction().add(
+      anchor.web3.SystemProgram.transfer({
+        fromPubkey: provider.wallet.publicKey,
+        toPubkey: impostor.publicKey,
+        lamports: anchor.web3.LAMPORTS_PER_SOL,
+      }),
+    ));
+    let error = null;
+    await initConfig(impostor).catch(e => error = e);
+    assert.equal(error.error.errorCode.code, "UnauthorizedAdmin");
+
+    await initConfig(null);
+
+    const configState = await program.account.globalConfig.fetch(config);
+    assert.ok(configState.admin.equals(provider.wallet.publicKey));
+    assert.equal(configState.pauseFlags.deposit, false);
+  })
+
+  it("allow-lists SOL and WETH against USDC", async () => {
+    const assets = [
+      { mint: WSOL_MINT, priceFeed: PYTH_SOL_PRICE_ACCOUNT, market: solUsdcMarketDecoded.ownAddress },
+      { mint: WETH_MINT, priceFeed: PYTH_ETH_PRICE_ACCOUNT, market: ethUsdcMarketDecoded.ownAddress },
+    ];
+    for (const asset of assets) {
+      await program.methods
+        .addSupportedAsset({ ...asset, pcMint: USDC_MINT })
+        .accounts({
+          config,
+          admin: provider.wallet.publicKey,
+        })
+        .rpc();
+    }
+
+    const configState = await program.account.globalConfig.fetch(config);
+    assert.equal(configState.supportedAssets.length, 2);
+    assert.ok(configState.supportedAssets[1].market.equals(ethUsdcMarketDecoded.ownAddress));
+  })
+
   it("mints assets (WETH, SOL)", async () => {
 
     //wrap sol
@@ -176,12 +312,53 @@ describe("asset_rebalancer", () => {
 
   })
 
+  it("rejects deposits while deposits are paused", async () => {
+    const setDepositPaused = (deposit: boolean) => program.methods
+      .setPauseFlags({ deposit, rebalance: false, refresh: false, withdraw: false })
+      .accounts({
+        config,
+        admin: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    await setDepositPaused(true);
+    let error = null;
+    await program.methods
+      .deposit(solPercentage, wEthPercentage, pcPercentage)
+      .accounts({
+        tokenAMint: NATIVE_MINT,
+        userTokenAAccount: wSolAccount,
+        tokenAVault: wSolVault,
+        tokenBMint: WETH_MINT,
+        userTokenBAccount: wethAccount,
+        tokenBVault: wEthVault,
+        pcVault: usdcVault,
+        pcMint: USDC_MINT,
+        vaultSigner: vaultSigner,
+        tokenAPythPrice: PYTH_SOL_PRICE_ACCOUNT,
+        tokenBPythPrice: PYTH_ETH_PRICE_ACCOUNT,
+        portfolioInfo,
+        rebalanceHistory,
+        config,
+        user: user.publicKey,
+        payer: user.publicKey,
+      })
+      .signers([user])
+      .rpc()
+      .catch(e => error = e);
+    assert.equal(error.error.errorCode.code, "ProgramPaused");
+
+    await setDepositPaused(false);
+    const configState = await program.account.globalConfig.fetch(config);
+    assert.equal(configState.pauseFlags.deposit, false);
+  })
+
   it("deposit tokens", async () => {
 
 
 
     await program.methods
-      .deposit(solPercentage, wEthPercentage, vaultSignerBump)
+      .deposit(solPercentage, wEthPercentage, pcPercentage)
       .accounts({
         tokenAMint: NATIVE_MINT,
         userTokenAAccount: wSolAccount,
@@ -195,7 +372,10 @@ describe("asset_rebalancer", () => {
         tokenAPythPrice: PYTH_SOL_PRICE_ACCOUNT,
         tokenBPythPrice: PYTH_ETH_PRICE_ACCOUNT,
         portfolioInfo,
+        rebalanceHistory,
+        config,
         user: user.publicKey,
+        payer: user.publicKey,
       })
 
       .signers([user,])
@@ -215,39 +395,327 @@ describe("asset_rebalancer", () => {
 
 
 
-  it("Initializes a fake market and open orders account", async () => {
-    const portfolioInfoState = await program.account.portfolioInfo.fetch(portfolioInfo)
+  it("onboards a portfolio with createPortfolio alone", async () => {
+    creator = Keypair.generate();
+    await program.provider.sendAndConfirm(new anchor.web3.Transaction().add(
+      anchor.web3.SystemProgram.transfer({
+        fromPubkey: provider.wallet.publicKey,
+        toPubkey: creator.publicKey,
+        lamports: anchor.web3.LAMPORTS_PER_SOL * 200,
+      }),
+    ));
+    creatorWSolAccount = await wrapSol(provider, creator, anchor.web3.LAMPORTS_PER_SOL * 100);
+    [, creatorWethAccount] = await createAssociatedTokenAccountAndMintTo(provider, WETH_SCALER * 100, WETH_MINT, creator);
+    [, creatorUsdcAccount] = await createAssociatedTokenAccountAndMintTo(provider, 0, USDC_MINT, creator);
+
+    const pda = await getPortfolioInfoAndSigner(program as anchor.Program, creator.publicKey);
+    createdPortfolioInfo = pda.key;
+    createdVaultSigner = pda.signer;
+    createdVaults = [
+      await getAssociatedTokenAddress(WSOL_MINT, createdVaultSigner, true),
+      await getAssociatedTokenAddress(WETH_MINT, createdVaultSigner, true),
+      await getAssociatedTokenAddress(USDC_MINT, createdVaultSigner, true),
+    ];
+    createdOpenOrdersA = await anchor.web3.PublicKey.findProgramAddress(
+      [Buffer.from(OPEN_ORDERS_A_STR), createdPortfolioInfo.toBuffer()], program.programId,
+    );
+    createdOpenOrdersB = await anchor.web3.PublicKey.findProgramAddress(
+      [Buffer.from(OPEN_ORDERS_B_STR), createdPortfolioInfo.toBuffer()], program.programId,
+    );
+    createdRebalanceHistory = (await anchor.web3.PublicKey.findProgramAddress(
+      [Buffer.from(REBALANCE_HISTORY_STR), createdPortfolioInfo.toBuffer()], program.programId,
+    ))[0];
+
+    // Each asset has to trade on its own allow-listed market.
+    let error = null;
+    await createCreatorPortfolio(ethUsdcMarketDecoded.ownAddress, solUsdcMarketDecoded.ownAddress)
+      .catch(e => error = e);
+    assert.equal(error.error.errorCode.code, "UnapprovedMarket");
+    assert.equal(await provider.connection.getAccountInfo(createdPortfolioInfo), null);
+
+    await createCreatorPortfolio(solUsdcMarketDecoded.ownAddress, ethUsdcMarketDecoded.ownAddress);
+
+    for (const vault of createdVaults) {
+      assert.notEqual(await provider.connection.getAccountInfo(vault), null);
+    }
+    const [wSolVaultBalance, wEthVaultBalance] = await Promise.all(
+      createdVaults.slice(0, 2).map(vault => provider.connection.getTokenAccountBalance(vault)),
+    );
+    assert.equal(wSolVaultBalance.value.amount, anchor.web3.LAMPORTS_PER_SOL * 100);
+    assert.equal(wEthVaultBalance.value.amount, WETH_SCALER * 100);
+
+    for (const [openOrders] of [createdOpenOrdersA, createdOpenOrdersB]) {
+      const openOrdersInfo = await provider.connection.getAccountInfo(openOrders);
+      assert.ok(openOrdersInfo.owner.equals(serumDexV3));
+    }
+
+    const portfolioInfoState = await program.account.portfolioInfo.fetch(createdPortfolioInfo);
+    assert.ok(portfolioInfoState.owner.equals(creator.publicKey));
+    assert.ok(portfolioInfoState.tokenAOpenOrders.equals(createdOpenOrdersA[0]));
+    assert.ok(portfolioInfoState.tokenBOpenOrders.equals(createdOpenOrdersB[0]));
+    assert.equal(portfolioInfoState.vaultSignerBump, pda.signerBump);
+    assert.equal(portfolioInfoState.openOrdersABump, createdOpenOrdersA[1]);
+    assert.equal(portfolioInfoState.openOrdersBBump, createdOpenOrdersB[1]);
+  })
 
+  it("updates the target allocation", async () => {
+    await program.methods
+      .updateAllocation(wEthPercentage, solPercentage, pcPercentage, new anchor.BN(0))
+      .accounts({
+        portfolioInfo,
+        user: user.publicKey,
+      })
+      .signers([user])
+      .rpc();
 
-    let bumps: {
-      vaultAuthority: number;
-      openOrdersA: number;
-      openOrdersB: number;
-    } = {
-      vaultAuthority: vaultSignerBump,
-      openOrdersA: vaultOpenOrderAAccount[1],
-      openOrdersB: vaultOpenOrderBAccount[1],
-    };
+    let portfolioInfoState = await program.account.portfolioInfo.fetch(portfolioInfo);
+    assert.equal(portfolioInfoState.tokenAPercentage, wEthPercentage);
+    assert.equal(portfolioInfoState.tokenBPercentage, solPercentage);
+
+    // Restore the original allocation for the rebalance below.
+    await program.methods
+      .updateAllocation(solPercentage, wEthPercentage, pcPercentage, new anchor.BN(0))
+      .accounts({
+        portfolioInfo,
+        user: user.publicKey,
+      })
+      .signers([user])
+      .rpc();
+
+    portfolioInfoState = await program.account.portfolioInfo.fetch(portfolioInfo);
+    assert.equal(portfolioInfoState.tokenAPercentage, solPercentage);
+    assert.equal(portfolioInfoState.tokenBPercentage, wEthPercentage);
+  })
+
+  it("sets the dust threshold", async () => {
+    // Orders worth less than 0.01 USDC are skipped on rebalance.
+    const dustThreshold = new anchor.BN(USDC_SCALER / 100);
+    await program.methods
+      .setDustThreshold(dustThreshold)
+      .accounts({
+        portfolioInfo,
+        user: user.publicKey,
+      })
+      .signers([user])
+      .rpc();
+
+    const portfolioInfoState = await program.account.portfolioInfo.fetch(portfolioInfo);
+    assert.ok(portfolioInfoState.dustThreshold.eq(dustThreshold));
+  })
+
+  it("sets the keeper fee", async () => {
+    await program.methods
+      .setKeeperFee(10)
+      .accounts({
+        portfolioInfo,
+        user: user.publicKey,
+      })
+      .signers([user])
+      .rpc();
+
+    const portfolioInfoState = await program.account.portfolioInfo.fetch(portfolioInfo);
+    assert.equal(portfolioInfoState.keeperFeeBps, 10);
+  })
+
+  it("sets the rebalance band", async () => {
+    let portfolioInfoState = await program.account.portfolioInfo.fetch(portfolioInfo);
+    // New portfolios only rebalance once a weight drifts 1% from its target.
+    assert.equal(portfolioInfoState.rebalanceBandBps, 100);
 
     await program.methods
-      .initAccounts(bumps)
+      .setRebalanceBand(50)
+      .accounts({
+        portfolioInfo,
+        user: user.publicKey,
+      })
+      .signers([user])
+      .rpc();
+
+    portfolioInfoState = await program.account.portfolioInfo.fetch(portfolioInfo);
+    assert.equal(portfolioInfoState.rebalanceBandBps, 50);
+  })
+
+  it("sets management and performance fees", async () => {
+    const setFees = (feeRecipient: anchor.web3.PublicKey, managementFeeBps: number, performanceFeeBps: number) =>
+      program.methods
+        .setFees(feeRecipient, managementFeeBps, performanceFeeBps)
+        .accounts({
+          collect: {
+            portfolioInfo,
+            tokenAPythPrice: PYTH_SOL_PRICE_ACCOUNT,
+            tokenBPythPrice: PYTH_ETH_PRICE_ACCOUNT,
+            tokenAVault: wSolVault,
+            tokenBVault: wEthVault,
+            pcVault: usdcVault,
+            vaultSigner,
+            feeWallet: usdcAccount,
+          },
+          authority: user.publicKey,
+          config,
+        })
+        .signers([user])
+        .rpc();
+
+    // No fee recipient is set yet, so the owner picks the first one.
+    await setFees(user.publicKey, 200, 2000);
+
+    const portfolioInfoState = await program.account.portfolioInfo.fetch(portfolioInfo);
+    assert.ok(portfolioInfoState.feeRecipient.equals(user.publicKey));
+    assert.equal(portfolioInfoState.managementFeeBps, 200);
+    assert.equal(portfolioInfoState.performanceFeeBps, 2000);
+    assert.ok(portfolioInfoState.lastFeeAccrualUnix.gtn(0));
+  })
+
+  it("only lets the fee recipient or the admin raise fees", async () => {
+    const setCreatorFees = (feeRecipient: anchor.web3.PublicKey, managementFeeBps: number, performanceFeeBps: number) =>
+      program.methods
+        .setFees(feeRecipient, managementFeeBps, performanceFeeBps)
+        .accounts({
+          collect: {
+            portfolioInfo: createdPortfolioInfo,
+            tokenAPythPrice: PYTH_SOL_PRICE_ACCOUNT,
+            tokenBPythPrice: PYTH_ETH_PRICE_ACCOUNT,
+            tokenAVault: createdVaults[0],
+            tokenBVault: createdVaults[1],
+            pcVault: createdVaults[2],
+            vaultSigner: createdVaultSigner,
+            feeWallet: creatorUsdcAccount,
+          },
+          authority: creator.publicKey,
+          config,
+        })
+        .signers([creator])
+        .rpc();
+
+    // The creator names the user as fee recipient, without charging anything
+    // yet.
+    await setCreatorFees(user.publicKey, 0, 0);
+
+    // From then on the owner can neither redirect the fees nor raise them.
+    for (const [feeRecipient, managementFeeBps, performanceFeeBps] of [
+      [creator.publicKey, 0, 0],
+      [user.publicKey, 200, 0],
+      [user.publicKey, 0, 2000],
+    ] as [anchor.web3.PublicKey, number, number][]) {
+      let error = null;
+      await setCreatorFees(feeRecipient, managementFeeBps, performanceFeeBps).catch(e => error = e);
+      assert.equal(error.error.errorCode.code, "UnauthorizedFeeChange");
+    }
+
+    const portfolioInfoState = await program.account.portfolioInfo.fetch(createdPortfolioInfo);
+    assert.ok(portfolioInfoState.feeRecipient.equals(user.publicKey));
+    assert.equal(portfolioInfoState.managementFeeBps, 0);
+    assert.equal(portfolioInfoState.performanceFeeBps, 0);
+  })
+
+  it("pauses and resumes the portfolio", async () => {
+    for (const paused of [true, false]) {
+      await program.methods
+        .setPortfolioPaused(paused)
+        .accounts({
+          portfolioInfo,
+          user: user.publicKey,
+        })
+        .signers([user])
+        .rpc();
+
+      const portfolioInfoState = await program.account.portfolioInfo.fetch(portfolioInfo);
+      assert.equal(portfolioInfoState.paused, paused);
+    }
+  })
+
+  it("transfers ownership once the new owner accepts", async () => {
+    const newOwner = anchor.web3.Keypair.generate();
+    for (const [owner, nextOwner] of [[user, newOwner], [newOwner, user]]) {
+      await program.methods
+        .transferOwnership(nextOwner.publicKey)
+        .accounts({
+          portfolioInfo,
+          user: owner.publicKey,
+        })
+        .signers([owner])
+        .rpc();
+
+      let portfolioInfoState = await program.account.portfolioInfo.fetch(portfolioInfo);
+      assert.ok(portfolioInfoState.owner.equals(owner.publicKey));
+      assert.ok(portfolioInfoState.pendingOwner.equals(nextOwner.publicKey));
+
+      await program.methods
+        .acceptOwnership()
+        .accounts({
+          portfolioInfo,
+          newOwner: nextOwner.publicKey,
+        })
+        .signers([nextOwner])
+        .rpc();
+
+      portfolioInfoState = await program.account.portfolioInfo.fetch(portfolioInfo);
+      assert.ok(portfolioInfoState.owner.equals(nextOwner.publicKey));
+      assert.ok(portfolioInfoState.creator.equals(user.publicKey));
+    }
+  })
+
+  it("sets and revokes the rebalance delegate", async () => {
+    const keeper = anchor.web3.Keypair.generate();
+    await program.methods
+      .setRebalanceDelegate(keeper.publicKey)
+      .accounts({
+        portfolioInfo,
+        user: user.publicKey,
+      })
+      .signers([user])
+      .rpc();
+
+    let portfolioInfoState = await program.account.portfolioInfo.fetch(portfolioInfo);
+    assert.ok(portfolioInfoState.rebalanceDelegate.equals(keeper.publicKey));
+    assert.deepEqual(portfolioInfoState.rebalancePolicy, { delegate: {} });
+
+    await program.methods
+      .revokeRebalanceDelegate()
+      .accounts({
+        portfolioInfo,
+        user: user.publicKey,
+      })
+      .signers([user])
+      .rpc();
+
+    portfolioInfoState = await program.account.portfolioInfo.fetch(portfolioInfo);
+    assert.ok(portfolioInfoState.rebalanceDelegate.equals(anchor.web3.PublicKey.default));
+    assert.deepEqual(portfolioInfoState.rebalancePolicy, { ownerOnly: {} });
+  })
+
+  it("Initializes a fake market and open orders account", async () => {
+    const initAccounts = (marketA: anchor.web3.PublicKey, marketB: anchor.web3.PublicKey) => program.methods
+      .initAccounts()
       .accounts({
         openOrdersA: vaultOpenOrderAAccount[0],
         openOrdersB: vaultOpenOrderBAccount[0],
         authority: vaultSigner,
-        marketA: orderBook.marketA.address,
-        marketB: orderBook.marketB.address,
+        marketA,
+        marketB,
         dexProgram: serumDexV3,
+        config,
         portfolioInfo,
         user: user.publicKey,
       })
       .signers([user,])
-      .rpc()
-      .catch(e => console.log(e));
+      .rpc();
+
+    // Only the allow-listed markets are accepted.
+    let error = null;
+    await initAccounts(orderBook.marketB.address, orderBook.marketA.address).catch(e => error = e);
+    assert.equal(error.error.errorCode.code, "UnapprovedMarket");
+
+    await initAccounts(orderBook.marketA.address, orderBook.marketB.address);
+
+    const portfolioInfoAfter = await program.account.portfolioInfo.fetch(portfolioInfo);
+    assert.equal(portfolioInfoAfter.vaultSignerBump, vaultSignerBump);
+    assert.equal(portfolioInfoAfter.openOrdersABump, vaultOpenOrderAAccount[1]);
+    assert.equal(portfolioInfoAfter.openOrdersBBump, vaultOpenOrderBAccount[1]);
 
 
     // await program.methods
-    //   .initAccounts(bumps)
+    //   .initAccounts()
     //   .accounts({
     //     openOrdersA: vaultOpenOrderAAccount[0],
     //     openOrdersB: vaultOpenOrderBAccount[0],
@@ -264,6 +732,48 @@ describe("asset_rebalancer", () => {
   })
 
 
+  it("sets a glide path the rebalance lands partway through", async () => {
+    // Glide from the current allocation to its mirror image, starting an hour
+    // ago and ending in an hour, so the rebalance below lands near halfway.
+    const now = Math.floor(Date.now() / 1000);
+    await program.methods
+      .setGlidePath(
+        { tokenAPercentage: solPercentage, tokenBPercentage: wEthPercentage, pcPercentage },
+        { tokenAPercentage: wEthPercentage, tokenBPercentage: solPercentage, pcPercentage },
+        new anchor.BN(now - 3600),
+        new anchor.BN(now + 3600),
+      )
+      .accounts({
+        portfolioInfo,
+        user: user.publicKey,
+      })
+      .signers([user])
+      .rpc();
+
+    const portfolioInfoState = await program.account.portfolioInfo.fetch(portfolioInfo);
+    assert.equal(portfolioInfoState.glideEndUnix.toNumber(), now + 3600);
+    // The targets only move once a rebalance runs.
+    assert.equal(portfolioInfoState.tokenAPercentage, solPercentage);
+
+    // A glide path longer than ten years is rejected.
+    let error = null;
+    await program.methods
+      .setGlidePath(
+        { tokenAPercentage: solPercentage, tokenBPercentage: wEthPercentage, pcPercentage },
+        { tokenAPercentage: wEthPercentage, tokenBPercentage: solPercentage, pcPercentage },
+        new anchor.BN(now),
+        new anchor.BN(now + 11 * 365 * 24 * 60 * 60),
+      )
+      .accounts({
+        portfolioInfo,
+        user: user.publicKey,
+      })
+      .signers([user])
+      .rpc()
+      .catch(e => error = e);
+    assert.equal(error.error.errorCode.code, "InvalidGlidePath");
+  })
+
   it("rebalance assets", async () => {
 
     // const newWsolBalanceBefore = await provider.connection.getTokenAccountBalance(wSolVault);
@@ -311,8 +821,13 @@ describe("asset_rebalancer", () => {
           pcWallet: usdcVault,
           vaultSigner: vaultSigner,
           portfolioInfo,
+          config,
+          rebalanceHistory,
           dexProgram: serumDexV3,
+          keeper: user.publicKey,
+          keeperFeeWallet: usdcAccount,
         })
+        .signers([user])
         .rpc()
         .catch(e => console.log(e));
 
@@ -322,11 +837,12 @@ describe("asset_rebalancer", () => {
 
     const newTokenAWorth = event.newTokenAWorth.toNumber();
     const newTokenBWorth = event.newTokenBWorth.toNumber();
-    const totalVaultWorth = newTokenAWorth + newTokenBWorth;
+    const newPcWorth = event.newPcWorth.toNumber();
+    const totalVaultWorth = newTokenAWorth + newTokenBWorth + newPcWorth;
 
 
-    const expectedTokenAPercentage = event.tokenAPercentage / 1000
-    const expectedTokenBPercentage = event.tokenBPercentage / 1000
+    const expectedTokenAPercentage = event.tokenAPercentage / 10000
+    const expectedTokenBPercentage = event.tokenBPercentage / 10000
 
     const newTokenAPercentage = newTokenAWorth / totalVaultWorth;
     const newTokenBPercentage = newTokenBWorth / totalVaultWorth;
@@ -335,6 +851,21 @@ describe("asset_rebalancer", () => {
     assert.ok(newTokenAPercentage >= expectedTokenAPercentage - 0.5 && newTokenAPercentage <= expectedTokenAPercentage + 0.5);
     assert.ok(newTokenBPercentage >= expectedTokenBPercentage - 0.5 && newTokenBPercentage <= expectedTokenBPercentage + 0.5);
 
+    // The targets moved partway along the glide path before the rebalance.
+    assert.ok(event.tokenAPercentage > solPercentage && event.tokenAPercentage < wEthPercentage);
+    assert.equal(event.tokenAPercentage + event.tokenBPercentage, solPercentage + wEthPercentage);
+    const glidingState = await program.account.portfolioInfo.fetch(portfolioInfo);
+    assert.equal(glidingState.tokenAPercentage, event.tokenAPercentage);
+    assert.ok(glidingState.glideEndUnix.toNumber() > 0);
+
+    // The rebalance is recorded in the portfolio's history.
+    const history = await program.account.rebalanceHistory.fetch(rebalanceHistory);
+    assert.ok(history.portfolioInfo.equals(portfolioInfo));
+    assert.equal(history.totalRebalances.toNumber(), 1);
+    const record = history.records[history.records.length - 1];
+    assert.ok(record.keeperFee.eq(event.keeperFee));
+    assert.ok(Math.abs(record.tokenAWeightAfter / 10000 - newTokenAPercentage) < 0.01);
+
     // const newWsolBalance = await provider.connection.getTokenAccountBalance(wSolVault);
     // console.log("SOL balance after: " + Number(newWsolBalance.value.amount) / SOL_SCALER);
 
@@ -346,6 +877,135 @@ describe("asset_rebalancer", () => {
 
 
 
+  it("returns the portfolio's balances and net asset value", async () => {
+    const balances = await program.methods
+      .getPortfolioBalances()
+      .accounts({
+        portfolioInfo,
+        tokenAVault: wSolVault,
+        tokenBVault: wEthVault,
+        pcVault: usdcVault,
+      })
+      .view();
+
+    const vaultUsdcBalance = await provider.connection.getTokenAccountBalance(usdcVault);
+    assert.equal(balances.pcAmount.toString(), vaultUsdcBalance.value.amount);
+    assert.ok(balances.netAssetValue.gte(balances.pcAmount));
+  })
+
+  it("tracks the portfolio's performance against buy-and-hold", async () => {
+    const stats = await program.methods
+      .getPortfolioStats()
+      .accounts({
+        portfolioInfo,
+        tokenAVault: wSolVault,
+        tokenBVault: wEthVault,
+        pcVault: usdcVault,
+      })
+      .view();
+
+    const portfolioInfoState = await program.account.portfolioInfo.fetch(portfolioInfo);
+    assert.ok(stats.totalDeposited.gtn(0));
+    assert.ok(stats.totalWithdrawn.isZero());
+    assert.ok(stats.realizedPnl.eq(portfolioInfoState.realizedPnl));
+    assert.ok(stats.netAssetValue.sub(stats.benchmarkNav).eq(stats.rebalancingPremium));
+
+    // The benchmark holds the amounts first deposited.
+    assert.ok(portfolioInfoState.tokenAInitialAmount.gtn(0));
+    assert.ok(portfolioInfoState.tokenBInitialAmount.gtn(0));
+  })
+
+  it("collects fees at the feeds' current prices", async () => {
+    const collectFees = () => program.methods
+      .collectFees()
+      .accounts({
+        portfolioInfo,
+        tokenAPythPrice: PYTH_SOL_PRICE_ACCOUNT,
+        tokenBPythPrice: PYTH_ETH_PRICE_ACCOUNT,
+        tokenAVault: wSolVault,
+        tokenBVault: wEthVault,
+        pcVault: usdcVault,
+        vaultSigner,
+        feeWallet: usdcAccount,
+      })
+      .rpc();
+
+    // The first collection only starts the high-water mark.
+    const before = await program.account.portfolioInfo.fetch(portfolioInfo);
+    assert.ok(before.highWaterMark.isZero());
+    await collectFees();
+    const bootstrapped = await program.account.portfolioInfo.fetch(portfolioInfo);
+    assert.ok(bootstrapped.highWaterMark.gtn(0));
+    assert.ok(bootstrapped.lastFeeAccrualUnix.gte(before.lastFeeAccrualUnix));
+
+    // Management fees keep accruing; whatever the quote vault can't pay is
+    // carried over rather than dropped.
+    let listener = null;
+    const event: any = await new Promise((resolve, reject) => {
+      listener = program.addEventListener("FeesAccrued", (event, _slot) => resolve(event));
+      collectFees().catch(reject);
+    });
+    await program.removeEventListener(listener);
+    const collected = await program.account.portfolioInfo.fetch(portfolioInfo);
+    assert.ok(event.performanceFee.isZero() || collected.highWaterMark.gte(bootstrapped.highWaterMark));
+    assert.ok(
+      bootstrapped.unpaidFees.add(event.managementFee).add(event.performanceFee)
+        .eq(event.feesPaid.add(collected.unpaidFees))
+    );
+  })
+
+  it("cancels and settles the portfolio's open orders", async () => {
+    const accounts = {
+      tokenAMarket: {
+        market: solUsdcMarketDecoded.ownAddress,
+        requestQueue: solUsdcMarketDecoded.requestQueue,
+        eventQueue: solUsdcMarketDecoded.eventQueue,
+        bids: solUsdcMarketDecoded.bids,
+        asks: solUsdcMarketDecoded.asks,
+        coinVault: solUsdcMarketDecoded.baseVault,
+        pcVault: solUsdcMarketDecoded.quoteVault,
+        vaultSigner: solUsdcMarketVaultSigner,
+        openOrders: vaultOpenOrderAAccount[0],
+        orderPayerTokenAccount: wSolVault,
+        coinWallet: wSolVault,
+      },
+      tokenBMarket: {
+        market: ethUsdcMarketDecoded.ownAddress,
+        requestQueue: ethUsdcMarketDecoded.requestQueue,
+        eventQueue: ethUsdcMarketDecoded.eventQueue,
+        bids: ethUsdcMarketDecoded.bids,
+        asks: ethUsdcMarketDecoded.asks,
+        coinVault: ethUsdcMarketDecoded.baseVault,
+        pcVault: ethUsdcMarketDecoded.quoteVault,
+        vaultSigner: ethUsdcMarketVaultSigner,
+        openOrders: vaultOpenOrderBAccount[0],
+        orderPayerTokenAccount: wEthVault,
+        coinWallet: wEthVault,
+      },
+      pcWallet: usdcVault,
+      vaultSigner: vaultSigner,
+      portfolioInfo,
+      dexProgram: serumDexV3,
+    };
+
+    await program.methods
+      .cancelAllOrders()
+      .accounts(accounts)
+      .rpc();
+
+    await program.methods
+      .settleFunds()
+      .accounts(accounts)
+      .rpc();
+
+    const openOrdersA = await OpenOrders.load(provider.connection, vaultOpenOrderAAccount[0], serumDexV3);
+    const openOrdersB = await OpenOrders.load(provider.connection, vaultOpenOrderBAccount[0], serumDexV3);
+    assert.equal(openOrdersA.baseTokenFree.toNumber(), 0);
+    assert.equal(openOrdersA.quoteTokenFree.toNumber(), 0);
+    assert.equal(openOrdersB.baseTokenFree.toNumber(), 0);
+    assert.equal(openOrdersB.quoteTokenFree.toNumber(), 0);
+  })
+
   it('withdraws tokens', async () => {
     const vaultSolBalance = await provider.connection.getTokenAccountBalance(wSolVault);
     const vaultUsdcBalance = await provider.connection.getTokenAccountBalance(usdcVault);
@@ -367,8 +1027,15 @@ describe("asset_rebalancer", () => {
         userPcAccount: usdcAccount,
         tokenBVault: wEthVault,
         pcVault: usdcVault,
+        // The user is the fee recipient, so fees owed are settled back to them.
+        tokenAPythPrice: PYTH_SOL_PRICE_ACCOUNT,
+        tokenBPythPrice: PYTH_ETH_PRICE_ACCOUNT,
+        feeTokenAWallet: wSolAccount,
+        feeTokenBWallet: wethAccount,
+        feePcWallet: usdcAccount,
         vaultSigner: vaultSigner,
         portfolioInfo,
+        config,
         user: user.publicKey,
       })
       .signers([user])
@@ -385,6 +1052,85 @@ describe("asset_rebalancer", () => {
 
   })
 
+  it("closes the createPortfolio portfolio and refunds its rent", async () => {
+    const marketAccounts = (
+      market: typeof solUsdcMarketDecoded,
+      marketVaultSigner: anchor.web3.PublicKey,
+      openOrders: anchor.web3.PublicKey,
+      vault: anchor.web3.PublicKey,
+    ) => [
+      market.ownAddress,
+      openOrders,
+      market.requestQueue,
+      market.eventQueue,
+      market.bids,
+      market.asks,
+      vault,
+      market.baseVault,
+      market.quoteVault,
+      marketVaultSigner,
+      vault,
+    ].map((pubkey, i) => ({ pubkey, isSigner: false, isWritable: i != 9 }));
+
+    const closedAccounts = [
+      createdPortfolioInfo,
+      createdRebalanceHistory,
+      ...createdVaults,
+      createdOpenOrdersA[0],
+      createdOpenOrdersB[0],
+    ];
+    const rent = (await Promise.all(
+      closedAccounts.map(account => provider.connection.getAccountInfo(account)),
+    )).reduce((sum, info) => sum + info.lamports, 0);
+    const formerLamports = await provider.connection.getBalance(creator.publicKey);
+
+    // The markets are only needed to close the open orders accounts.
+    await closeCreatorPortfolio([
+      ...marketAccounts(solUsdcMarketDecoded, solUsdcMarketVaultSigner, createdOpenOrdersA[0], createdVaults[0]),
+      ...marketAccounts(ethUsdcMarketDecoded, ethUsdcMarketVaultSigner, createdOpenOrdersB[0], createdVaults[1]),
+      { pubkey: serumDexV3, isSigner: false, isWritable: false },
+    ]);
+
+    for (const account of closedAccounts) {
+      assert.equal(await provider.connection.getAccountInfo(account), null);
+    }
+    // The creator paid the transaction fee out of the refunded rent.
+    const newLamports = await provider.connection.getBalance(creator.publicKey);
+    assert.ok(newLamports > formerLamports);
+    assert.ok(newLamports <= formerLamports + rent);
+  })
+
+  it("closes a portfolio whose open orders accounts were closed first", async () => {
+    // Closing the first portfolio freed the creator's address.
+    await createCreatorPortfolio(solUsdcMarketDecoded.ownAddress, ethUsdcMarketDecoded.ownAddress);
+
+    await program.methods
+      .closeAccount()
+      .accounts({
+        openOrdersA: createdOpenOrdersA[0],
+        openOrdersB: createdOpenOrdersB[0],
+        authority: createdVaultSigner,
+        marketA: solUsdcMarketDecoded.ownAddress,
+        marketB: ethUsdcMarketDecoded.ownAddress,
+        dexProgram: serumDexV3,
+        portfolioInfo: createdPortfolioInfo,
+        user: creator.publicKey,
+      })
+      .signers([creator])
+      .rpc();
+
+    const portfolioInfoState = await program.account.portfolioInfo.fetch(createdPortfolioInfo);
+    assert.ok(portfolioInfoState.tokenAOpenOrders.equals(anchor.web3.PublicKey.default));
+    assert.ok(portfolioInfoState.tokenBOpenOrders.equals(anchor.web3.PublicKey.default));
+
+    // Without open orders accounts, no markets are needed.
+    await closeCreatorPortfolio([]);
+
+    for (const account of [createdPortfolioInfo, createdRebalanceHistory, ...createdVaults]) {
+      assert.equal(await provider.connection.getAccountInfo(account), null);
+    }
+  })
+
 });
 
 